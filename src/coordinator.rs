@@ -0,0 +1,369 @@
+use crate::distributed_transaction::DistributedTransaction;
+use crate::error::MSMQError;
+use crate::transaction::ResourceManager;
+use crate::transaction_log::TransactionLog;
+use crate::{ReceivedMessage, Response, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Write;
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+#[derive(Serialize, Deserialize, Debug)]
+enum CoordinatorRequest {
+    Begin,
+    /// Adds the queue server listening on `participant` to the transaction.
+    Enlist {
+        transaction_id: String,
+        participant: String,
+    },
+    Commit {
+        transaction_id: String,
+    },
+    Abort {
+        transaction_id: String,
+    },
+}
+
+/// Drives distributed transactions across queue servers in other processes, like MSDTC.
+///
+/// Clients begin a transaction here, enlist the servers they use and then work on each
+/// server under the transaction's id. On commit every server votes by preparing its branch
+/// of the transaction. The outcome is recorded in the coordinator's log and sent again to
+/// the servers if the coordinator restarts before all of them received it.
+pub struct Coordinator {
+    log: Arc<TransactionLog>,
+    active: Mutex<HashMap<String, Arc<DistributedTransaction>>>,
+}
+
+impl Coordinator {
+    /// Opens the coordinator with its log at `log_path`, finishing the transactions a previous
+    /// run left behind.
+    pub fn open(log_path: impl AsRef<Path>) -> Result<Self> {
+        let coordinator = Self {
+            log: Arc::new(TransactionLog::load(log_path)?),
+            active: Mutex::new(HashMap::new()),
+        };
+        coordinator.recover();
+        Ok(coordinator)
+    }
+
+    /// Starts a transaction, returning its id.
+    pub fn begin(&self) -> Result<String> {
+        let transaction = DistributedTransaction::new().with_log(Arc::clone(&self.log));
+        let transaction_id = transaction.id().to_string();
+        self.active
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?
+            .insert(transaction_id.clone(), Arc::new(transaction));
+        Ok(transaction_id)
+    }
+
+    /// Starts the branch of `transaction_id` on the server listening on `participant` and
+    /// adds the server to the transaction.
+    pub fn enlist(&self, transaction_id: &str, participant: &str) -> Result<()> {
+        let transaction = self
+            .active
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?
+            .get(transaction_id)
+            .cloned()
+            .ok_or(MSMQError::TransactionNotFound)?;
+
+        let server = RemoteParticipant {
+            address: participant.to_string(),
+        };
+        server.request(ReceivedMessage::JoinTransaction {
+            transaction_id: transaction_id.to_string(),
+        })?;
+        transaction.enlist(participant, Arc::new(server))
+    }
+
+    /// Commits `transaction_id` with two-phase commit. If any server votes no, it is
+    /// aborted on all of them.
+    pub fn commit(&self, transaction_id: &str) -> Result<()> {
+        self.take(transaction_id)?.commit()
+    }
+
+    pub fn abort(&self, transaction_id: &str) -> Result<()> {
+        self.take(transaction_id)?.abort()
+    }
+
+    fn take(&self, transaction_id: &str) -> Result<Arc<DistributedTransaction>> {
+        self.active
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?
+            .remove(transaction_id)
+            .ok_or(MSMQError::TransactionNotFound)
+    }
+
+    /// Sends the outcome of every transaction still in the log to its servers: committed
+    /// ones are committed, ones that never got a decision are aborted. Transactions whose
+    /// servers can't be reached stay in the log for the next restart.
+    fn recover(&self) {
+        for (transaction_id, participants, committed) in self.log.transactions() {
+            let mut finished = true;
+            for address in participants {
+                let server = RemoteParticipant { address };
+                let result = if committed {
+                    server.commit(&transaction_id)
+                } else {
+                    server.rollback(&transaction_id)
+                };
+                if let Err(e) = result {
+                    tracing::warn!(
+                        "Failed to finish transaction {} on {}: {}",
+                        transaction_id,
+                        server.address,
+                        e
+                    );
+                    finished = false;
+                }
+            }
+
+            if finished {
+                if let Err(e) = self.log.forget(&transaction_id) {
+                    tracing::error!("Failed to forget transaction {}: {}", transaction_id, e);
+                }
+            }
+        }
+    }
+
+    pub fn start(self: Arc<Self>, address: &str) -> Result<()> {
+        let listener = TcpListener::bind(address)?;
+        println!("Coordinator listening on {}", address);
+
+        for stream in listener.incoming() {
+            let stream = stream?;
+            let coordinator = Arc::clone(&self);
+            thread::spawn(move || {
+                if let Err(e) = coordinator.handle_client(stream) {
+                    eprintln!("Error handling client: {}", e);
+                }
+            });
+        }
+        Ok(())
+    }
+
+    fn handle_client(&self, mut stream: TcpStream) -> Result<()> {
+        let reader = stream.try_clone()?;
+        let requests =
+            serde_json::Deserializer::from_reader(reader).into_iter::<CoordinatorRequest>();
+        for request in requests {
+            let result = match request? {
+                CoordinatorRequest::Begin => self
+                    .begin()
+                    .map(|transaction_id| Response::TransactionStarted { transaction_id }),
+                CoordinatorRequest::Enlist {
+                    transaction_id,
+                    participant,
+                } => self
+                    .enlist(&transaction_id, &participant)
+                    .map(|_| Response::Success),
+                CoordinatorRequest::Commit { transaction_id } => {
+                    self.commit(&transaction_id).map(|_| Response::Success)
+                }
+                CoordinatorRequest::Abort { transaction_id } => {
+                    self.abort(&transaction_id).map(|_| Response::Success)
+                }
+            };
+            let response = result.unwrap_or_else(|e| Response::Error {
+                message: e.to_string(),
+            });
+
+            stream.write_all(&serde_json::to_vec(&response)?)?;
+        }
+        Ok(())
+    }
+}
+
+pub fn run_coordinator(log_path: &str, address: &str) -> Result<()> {
+    Arc::new(Coordinator::open(log_path)?).start(address)
+}
+
+/// A queue server taking part in a transaction, driven over TCP.
+struct RemoteParticipant {
+    address: String,
+}
+
+impl RemoteParticipant {
+    fn request(&self, request: ReceivedMessage) -> Result<()> {
+        let mut stream = TcpStream::connect(&self.address)?;
+        stream.write_all(&serde_json::to_vec(&request)?)?;
+
+        let response = serde_json::Deserializer::from_reader(&stream)
+            .into_iter()
+            .next()
+            .ok_or_else(|| {
+                MSMQError::Custom(format!("{} closed the connection", self.address))
+            })??;
+        match response {
+            Response::Success => Ok(()),
+            Response::Error { message } => Err(MSMQError::Custom(message)),
+            response => Err(MSMQError::Custom(format!(
+                "Unexpected response from {}: {:?}",
+                self.address, response
+            ))),
+        }
+    }
+}
+
+impl ResourceManager for RemoteParticipant {
+    fn prepare(&self, txn_id: &str) -> Result<()> {
+        self.request(ReceivedMessage::Prepare {
+            transaction_id: txn_id.to_string(),
+        })
+    }
+
+    fn commit(&self, txn_id: &str) -> Result<()> {
+        self.request(ReceivedMessage::CommitPrepared {
+            transaction_id: txn_id.to_string(),
+        })
+    }
+
+    fn rollback(&self, txn_id: &str) -> Result<()> {
+        self.request(ReceivedMessage::AbortPrepared {
+            transaction_id: txn_id.to_string(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::QueueServer;
+    use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn start_queue_server(dir: &TempDir, name: &str, address: &str) {
+        let queue_path = dir.path().join(name).to_string_lossy().to_string();
+        let address = address.to_string();
+        thread::spawn(move || {
+            let server = QueueServer::transactional(&queue_path).unwrap();
+            server.start(&address).unwrap();
+        });
+    }
+
+    fn call<T: Serialize>(address: &str, request: &T) -> Response {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(&serde_json::to_vec(request).unwrap())
+            .unwrap();
+        serde_json::Deserializer::from_reader(stream)
+            .into_iter()
+            .next()
+            .unwrap()
+            .unwrap()
+    }
+
+    fn enqueue(address: &str, transaction_id: &str, content: &str) {
+        let response = call(
+            address,
+            &ReceivedMessage::EnqueueInTransaction {
+                transaction_id: transaction_id.to_string(),
+                content: content.to_string(),
+            },
+        );
+        assert!(matches!(response, Response::Success));
+    }
+
+    fn dequeue(address: &str) -> Option<String> {
+        match call(address, &ReceivedMessage::Dequeue) {
+            Response::Dequeued { content } => Some(content),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_commit_across_servers() {
+        let dir = tempfile::tempdir().unwrap();
+        let (orders, invoices, coordinator) =
+            ("127.0.0.1:8013", "127.0.0.1:8014", "127.0.0.1:8015");
+        start_queue_server(&dir, "orders.msmq", orders);
+        start_queue_server(&dir, "invoices.msmq", invoices);
+        let log_path = dir.path().join("dtc.log").to_string_lossy().to_string();
+        thread::spawn(move || run_coordinator(&log_path, coordinator).unwrap());
+        thread::sleep(Duration::from_millis(100));
+
+        let Response::TransactionStarted { transaction_id } =
+            call(coordinator, &CoordinatorRequest::Begin)
+        else {
+            panic!("transaction wasn't started");
+        };
+        for participant in [orders, invoices] {
+            let response = call(
+                coordinator,
+                &CoordinatorRequest::Enlist {
+                    transaction_id: transaction_id.clone(),
+                    participant: participant.to_string(),
+                },
+            );
+            assert!(matches!(response, Response::Success));
+        }
+        enqueue(orders, &transaction_id, "Order");
+        enqueue(invoices, &transaction_id, "Invoice");
+        assert_eq!(dequeue(orders), None);
+
+        let response = call(coordinator, &CoordinatorRequest::Commit { transaction_id });
+        assert!(matches!(response, Response::Success));
+        assert_eq!(dequeue(orders).as_deref(), Some("Order"));
+        assert_eq!(dequeue(invoices).as_deref(), Some("Invoice"));
+    }
+
+    #[test]
+    fn test_no_vote_aborts_every_server() {
+        let dir = tempfile::tempdir().unwrap();
+        let (orders, invoices) = ("127.0.0.1:8016", "127.0.0.1:8017");
+        start_queue_server(&dir, "orders.msmq", orders);
+        start_queue_server(&dir, "invoices.msmq", invoices);
+        thread::sleep(Duration::from_millis(100));
+
+        let coordinator = Coordinator::open(dir.path().join("dtc.log")).unwrap();
+        let transaction_id = coordinator.begin().unwrap();
+        coordinator.enlist(&transaction_id, orders).unwrap();
+        coordinator.enlist(&transaction_id, invoices).unwrap();
+        enqueue(orders, &transaction_id, "Order");
+        enqueue(invoices, &transaction_id, "Invoice");
+
+        // invoices loses its branch, so it can't prepare
+        call(
+            invoices,
+            &ReceivedMessage::AbortPrepared {
+                transaction_id: transaction_id.clone(),
+            },
+        );
+
+        assert!(coordinator.commit(&transaction_id).is_err());
+        assert_eq!(dequeue(orders), None);
+        assert_eq!(dequeue(invoices), None);
+    }
+
+    #[test]
+    fn test_restarted_coordinator_finishes_logged_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let orders = "127.0.0.1:8018";
+        start_queue_server(&dir, "orders.msmq", orders);
+        thread::sleep(Duration::from_millis(100));
+        let log_path = dir.path().join("dtc.log");
+
+        {
+            let coordinator = Coordinator::open(&log_path).unwrap();
+            let transaction_id = coordinator.begin().unwrap();
+            coordinator.enlist(&transaction_id, orders).unwrap();
+            enqueue(orders, &transaction_id, "Order");
+
+            // crash after deciding to commit, before telling the server
+            let transaction = coordinator.take(&transaction_id).unwrap();
+            transaction.prepare().unwrap();
+            coordinator.log.record_commit(&transaction_id).unwrap();
+            std::mem::forget(transaction);
+        }
+        assert_eq!(dequeue(orders), None);
+
+        let coordinator = Coordinator::open(&log_path).unwrap();
+        assert_eq!(dequeue(orders).as_deref(), Some("Order"));
+        assert!(coordinator.log.transactions().is_empty());
+    }
+}
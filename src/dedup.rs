@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Remembers the dedup keys a queue has accepted during the last `window`.
+pub(crate) struct DeduplicationWindow {
+    window: Duration,
+    keys: Mutex<Keys>,
+}
+
+#[derive(Default)]
+struct Keys {
+    /// Dedup key to the time it was first seen, in milliseconds since the unix epoch.
+    seen: HashMap<String, u64>,
+    /// Keys of messages that are being stored, with the time they were claimed.
+    in_flight: HashMap<String, u64>,
+}
+
+impl DeduplicationWindow {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            keys: Mutex::new(Keys::default()),
+        }
+    }
+
+    /// Claims `key` for a message about to be stored, returning when it was claimed, or
+    /// `None` if it was already seen within the window or is being stored right now. The
+    /// claim has to be [`confirm`](Self::confirm)ed once the message is stored, or
+    /// [`release`](Self::release)d if that failed.
+    pub fn claim(&self, key: &str) -> Option<u64> {
+        let now = now_millis();
+        let window = self.window.as_millis() as u64;
+        let mut keys = self.keys.lock().expect("Failed to lock dedup window");
+        keys.seen
+            .retain(|_, seen_at| now.saturating_sub(*seen_at) < window);
+
+        if keys.seen.contains_key(key) || keys.in_flight.contains_key(key) {
+            return None;
+        }
+        keys.in_flight.insert(key.to_string(), now);
+        Some(now)
+    }
+
+    /// Remembers a claimed key now that its message is stored.
+    pub fn confirm(&self, key: &str) {
+        let mut keys = self.keys.lock().expect("Failed to lock dedup window");
+        if let Some(seen_at) = keys.in_flight.remove(key) {
+            keys.seen.insert(key.to_string(), seen_at);
+        }
+    }
+
+    /// Gives up a claimed key whose message couldn't be stored, so it can be sent again.
+    pub fn release(&self, key: &str) {
+        self.keys
+            .lock()
+            .expect("Failed to lock dedup window")
+            .in_flight
+            .remove(key);
+    }
+
+    /// Keys seen within the window, including claimed ones: a snapshot taken while they
+    /// are in flight already holds their messages.
+    pub fn snapshot(&self) -> HashMap<String, u64> {
+        let keys = self.keys.lock().expect("Failed to lock dedup window");
+        keys.seen
+            .iter()
+            .chain(&keys.in_flight)
+            .map(|(key, seen_at)| (key.clone(), *seen_at))
+            .collect()
+    }
+
+    pub fn restore(&self, seen: HashMap<String, u64>) {
+        self.keys
+            .lock()
+            .expect("Failed to lock dedup window")
+            .seen
+            .extend(seen);
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys_expire_after_window() {
+        let dedup = DeduplicationWindow::new(Duration::from_millis(50));
+        assert!(dedup.claim("key").is_some());
+        assert!(dedup.claim("key").is_none());
+        dedup.confirm("key");
+        assert!(dedup.claim("key").is_none());
+
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(dedup.claim("key").is_some());
+    }
+
+    #[test]
+    fn test_released_key_can_be_claimed_again() {
+        let dedup = DeduplicationWindow::new(Duration::from_secs(60));
+        assert!(dedup.claim("key").is_some());
+        dedup.release("key");
+        assert!(dedup.claim("key").is_some());
+    }
+}
@@ -0,0 +1,352 @@
+use crate::error::MSMQError;
+use crate::message::Message;
+use crate::multicast_group::GroupMember;
+use crate::storage::{read_json, write_json};
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Names that stand for other destinations.
+#[derive(Serialize, Deserialize, Default)]
+struct Names {
+    /// Alias to the name it currently points at.
+    #[serde(default)]
+    aliases: HashMap<String, String>,
+    /// Distribution list to its members, which may be queues, aliases or other lists.
+    #[serde(default)]
+    lists: HashMap<String, Vec<String>>,
+    /// Queue servers registered by name, to their address.
+    #[serde(default)]
+    remotes: HashMap<String, String>,
+}
+
+/// Resolves destination names to queues. A name is a queue registered with the directory,
+/// an alias pointing at another name, or a distribution list of names. Aliases can be
+/// repointed at any time, e.g. while a queue is being migrated.
+///
+/// Aliases, lists and the addresses of remote servers are saved to the directory's file, if
+/// it has one; queues register again when they start.
+#[derive(Clone, Default)]
+pub struct Directory {
+    /// Registered queues, keyed by queue name.
+    queues: Arc<Mutex<HashMap<String, Arc<dyn GroupMember>>>>,
+    names: Arc<Mutex<Names>>,
+    path: Option<PathBuf>,
+}
+
+impl Directory {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Opens a directory whose aliases and lists are kept in `path`.
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        Ok(Self {
+            queues: Arc::new(Mutex::new(HashMap::new())),
+            names: Arc::new(Mutex::new(read_json(&path)?)),
+            path: Some(path),
+        })
+    }
+
+    /// Points `alias` at `target`, replacing where it pointed before. The target doesn't have
+    /// to exist until something is sent to the alias.
+    pub fn set_alias(&self, alias: &str, target: &str) -> Result<()> {
+        self.update(alias, true, |names| {
+            names.aliases.insert(alias.to_string(), target.to_string());
+        })
+    }
+
+    /// Defines the distribution list `name`, replacing its previous members.
+    pub fn define_list(&self, name: &str, members: &[&str]) -> Result<()> {
+        let members = members.iter().map(|member| member.to_string()).collect();
+        self.update(name, false, |names| {
+            names.lists.insert(name.to_string(), members);
+        })
+    }
+
+    /// Removes the alias, distribution list or remote server `name`.
+    pub fn remove(&self, name: &str) -> Result<()> {
+        let mut queues = self
+            .queues
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?;
+        let mut names = self
+            .names
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?;
+        if names.remotes.remove(name).is_some() {
+            queues.remove(name);
+        } else if names.aliases.remove(name).is_none() && names.lists.remove(name).is_none() {
+            return Err(MSMQError::QueueNotFound(name.to_string()));
+        }
+        self.save(&names)
+    }
+
+    /// Names of the queues `destination` stands for, each once, in the order they are listed.
+    pub fn resolve(&self, destination: &str) -> Result<Vec<String>> {
+        let queues = self
+            .queues
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?;
+        let names = self
+            .names
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?;
+        let mut resolved = Vec::new();
+        resolve_into(destination, &queues, &names, &mut Vec::new(), &mut resolved)?;
+        Ok(resolved)
+    }
+
+    /// Sends a copy of `message` to every queue `destination` stands for.
+    pub fn send(&self, destination: &str, message: Message) -> Result<()> {
+        let mut result = Ok(());
+        for (_, outcome) in self.send_each(destination, message)? {
+            if let Err(e) = outcome {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// Like [`send`](Self::send), returning how each queue took its copy, by queue name.
+    pub(crate) fn send_each(
+        &self,
+        destination: &str,
+        message: Message,
+    ) -> Result<Vec<(String, Result<()>)>> {
+        let resolved = self.resolve(destination)?;
+        let members: Vec<_> = {
+            let queues = self
+                .queues
+                .lock()
+                .map_err(|e| MSMQError::Custom(e.to_string()))?;
+            resolved
+                .into_iter()
+                .filter_map(|name| Some((name.clone(), queues.get(&name).cloned()?)))
+                .collect()
+        };
+        Ok(members
+            .into_iter()
+            .map(|(name, member)| {
+                let outcome = member.deliver(message.clone());
+                if let Err(e) = &outcome {
+                    tracing::warn!("Failed to deliver to {} for {}: {}", name, destination, e);
+                }
+                (name, outcome)
+            })
+            .collect())
+    }
+
+    pub(crate) fn register(&self, name: &str, queue: Arc<dyn GroupMember>) -> Result<()> {
+        let mut queues = self
+            .queues
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?;
+        let names = self
+            .names
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?;
+        let taken = queues
+            .get(name)
+            .is_some_and(|registered| registered.member_id() != queue.member_id());
+        if taken || names.aliases.contains_key(name) || names.lists.contains_key(name) {
+            return Err(MSMQError::NameInUse(name.to_string()));
+        }
+        queues.insert(name.to_string(), queue);
+        Ok(())
+    }
+
+    /// Registers the queue server at `address` under `name`, remembering its address so it
+    /// can be registered again after a restart.
+    pub(crate) fn register_remote(
+        &self,
+        name: &str,
+        address: &str,
+        server: Arc<dyn GroupMember>,
+    ) -> Result<()> {
+        self.register(name, server)?;
+        let mut names = self
+            .names
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?;
+        names.remotes.insert(name.to_string(), address.to_string());
+        self.save(&names)
+    }
+
+    /// Names and addresses of the remote servers registered with the directory.
+    pub(crate) fn remotes(&self) -> Result<Vec<(String, String)>> {
+        Ok(self
+            .names
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?
+            .remotes
+            .iter()
+            .map(|(name, address)| (name.clone(), address.clone()))
+            .collect())
+    }
+
+    pub(crate) fn unregister(&self, name: &str) -> Result<()> {
+        self.queues
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?
+            .remove(name);
+        Ok(())
+    }
+
+    /// Applies `change` to the names after checking that `name` isn't taken by a queue or
+    /// by a name of the other kind, then saves them.
+    fn update<F>(&self, name: &str, alias: bool, change: F) -> Result<()>
+    where
+        F: FnOnce(&mut Names),
+    {
+        let queues = self
+            .queues
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?;
+        let mut names = self
+            .names
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?;
+        let other_kind = if alias {
+            names.lists.contains_key(name)
+        } else {
+            names.aliases.contains_key(name)
+        };
+        if queues.contains_key(name) || other_kind {
+            return Err(MSMQError::NameInUse(name.to_string()));
+        }
+        change(&mut names);
+        self.save(&names)
+    }
+
+    fn save(&self, names: &Names) -> Result<()> {
+        match &self.path {
+            Some(path) => write_json(path, names),
+            None => Ok(()),
+        }
+    }
+}
+
+fn resolve_into(
+    name: &str,
+    queues: &HashMap<String, Arc<dyn GroupMember>>,
+    names: &Names,
+    visiting: &mut Vec<String>,
+    resolved: &mut Vec<String>,
+) -> Result<()> {
+    if visiting.iter().any(|visited| visited == name) {
+        return Err(MSMQError::DestinationLoop(name.to_string()));
+    }
+
+    if queues.contains_key(name) {
+        if !resolved.iter().any(|queue| queue == name) {
+            resolved.push(name.to_string());
+        }
+        return Ok(());
+    }
+
+    visiting.push(name.to_string());
+    if let Some(target) = names.aliases.get(name) {
+        resolve_into(target, queues, names, visiting, resolved)?;
+    } else if let Some(members) = names.lists.get(name) {
+        for member in members {
+            resolve_into(member, queues, names, visiting, resolved)?;
+        }
+    } else {
+        return Err(MSMQError::QueueNotFound(name.to_string()));
+    }
+    visiting.pop();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::QueueOps;
+    use crate::queue_builder::QueueBuilder;
+
+    #[test]
+    fn test_nested_lists_and_repointed_alias() {
+        let directory = Directory::new();
+        let mut old_orders = QueueBuilder::new("old_orders").build();
+        let mut new_orders = QueueBuilder::new("new_orders").build();
+        let mut audit = QueueBuilder::new("audit").build();
+        for queue in [&mut old_orders, &mut new_orders, &mut audit] {
+            queue.register(&directory).unwrap();
+        }
+
+        directory.set_alias("orders", "old_orders").unwrap();
+        directory.define_list("backoffice", &["audit"]).unwrap();
+        directory
+            .define_list("everyone", &["orders", "backoffice", "audit"])
+            .unwrap();
+        assert_eq!(
+            directory.resolve("everyone").unwrap(),
+            ["old_orders", "audit"]
+        );
+
+        directory.send("everyone", Message::new("First")).unwrap();
+        directory.set_alias("orders", "new_orders").unwrap();
+        directory.send("orders", Message::new("Second")).unwrap();
+        assert_eq!(old_orders.receive().unwrap().content(), "First");
+        assert_eq!(new_orders.receive().unwrap().content(), "Second");
+        assert_eq!(audit.message_count().unwrap(), 1);
+
+        assert!(matches!(
+            directory.set_alias("audit", "new_orders"),
+            Err(MSMQError::NameInUse(_))
+        ));
+        assert!(matches!(
+            directory.send("invoices", Message::new("Lost")),
+            Err(MSMQError::QueueNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_other_queue_with_a_registered_name_is_rejected() {
+        let directory = Directory::new();
+        let mut orders = QueueBuilder::new("orders").build();
+        let mut other_orders = QueueBuilder::new("orders").build();
+        orders.register(&directory).unwrap();
+        orders.clone().register(&directory).unwrap();
+
+        assert!(matches!(
+            other_orders.register(&directory),
+            Err(MSMQError::NameInUse(name)) if name == "orders"
+        ));
+        directory.send("orders", Message::new("Order")).unwrap();
+        assert_eq!(orders.message_count().unwrap(), 1);
+        assert_eq!(other_orders.message_count().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_loops_are_rejected_on_send() {
+        let directory = Directory::new();
+        directory.set_alias("a", "b").unwrap();
+        directory.define_list("b", &["a"]).unwrap();
+
+        assert!(matches!(
+            directory.send("a", Message::new("Looping")),
+            Err(MSMQError::DestinationLoop(_))
+        ));
+    }
+
+    #[test]
+    fn test_names_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("names.directory");
+        {
+            let directory = Directory::open(&path).unwrap();
+            directory.set_alias("orders", "orders_v2").unwrap();
+            directory.define_list("everyone", &["orders"]).unwrap();
+        }
+
+        let directory = Directory::open(&path).unwrap();
+        let mut orders = QueueBuilder::new("orders_v2").build();
+        orders.register(&directory).unwrap();
+        assert_eq!(directory.resolve("everyone").unwrap(), ["orders_v2"]);
+    }
+}
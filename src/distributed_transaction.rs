@@ -1,17 +1,486 @@
+use crate::transaction::{ResourceManager, TransactionCore, TransactionState};
+use crate::transaction_log::TransactionLog;
 use crate::Result;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
 
-pub struct DistributedTransaction {}
+/// Transaction coordinated with two-phase commit: every enlisted participant is asked to
+/// [`prepare`](DistributedTransaction::prepare) before any of them commits, and a single
+/// failed prepare rolls the transaction back everywhere. Dropping an unfinished
+/// distributed transaction aborts it.
+pub struct DistributedTransaction {
+    core: Arc<TransactionCore>,
+}
+
+impl Default for DistributedTransaction {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl DistributedTransaction {
     pub fn new() -> Self {
-        Self {}
+        Self {
+            core: Arc::new(TransactionCore::two_phase()),
+        }
     }
 
-    pub fn commit(&self) -> Result<()> {
+    /// Starts a transaction that is aborted if it hasn't prepared `timeout` from now, like
+    /// [`with_timeout`](Self::with_timeout).
+    pub fn new_with_timeout(timeout: Duration) -> Self {
+        Self::new().with_timeout(timeout)
+    }
+
+    /// Local branch of the transaction `id` driven by a coordinator in another process.
+    pub(crate) fn branch(id: &str) -> Self {
+        Self {
+            core: Arc::new(TransactionCore::two_phase().with_id(id)),
+        }
+    }
+
+    /// Persists the commit decision in `log` before it is applied.
+    pub fn with_log(self, log: Arc<TransactionLog>) -> Self {
+        self.core.set_log(log);
+        self
+    }
+
+    /// Aborts the transaction if it hasn't prepared `timeout` after it was created; a later
+    /// commit fails with [`MSMQError::TransactionTimedOut`](crate::error::MSMQError).
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.core.set_timeout(timeout);
+        self
+    }
+
+    pub fn id(&self) -> &str {
+        self.core.id()
+    }
+
+    pub fn state(&self) -> TransactionState {
+        self.core.state()
+    }
+
+    /// Adds `resource` to the transaction under `name`. Enlisting the same name twice keeps
+    /// the first resource.
+    pub fn enlist(&self, name: &str, resource: Arc<dyn ResourceManager>) -> Result<()> {
+        self.core.enlist(name, resource)
+    }
+
+    pub(crate) fn enlist_with<R>(
+        &self,
+        name: &str,
+        resource: Arc<dyn ResourceManager>,
+        work: impl FnOnce() -> Result<R>,
+    ) -> Result<R> {
+        self.core.enlist_with(name, resource, work)
+    }
+
+    pub(crate) fn set_default_timeout(&self, timeout: Duration) {
+        self.core.set_default_timeout(timeout);
+    }
+
+    /// Resolves the transactions `resources`, given with the names they were enlisted under,
+    /// report as prepared after a restart: those whose commit is recorded in `log` are
+    /// committed, those `log` has no record of are rolled back, and those still in doubt are
+    /// left for [`resolve`](Self::resolve). A commit record is only dropped once every
+    /// participant it lists has recovered, which may take several calls.
+    pub fn recover(
+        log: &TransactionLog,
+        resources: &[(&str, Arc<dyn ResourceManager>)],
+    ) -> Result<()> {
+        let mut acknowledged: HashMap<String, Vec<String>> = HashMap::new();
+        for (name, resource) in resources {
+            for txn_id in resource.recover()? {
+                if log.is_committed(&txn_id) {
+                    tracing::info!("Completing committed transaction {} on {}", txn_id, name);
+                    resource.commit(&txn_id)?;
+                } else if log.is_in_doubt(&txn_id) {
+                    tracing::warn!("Transaction {} is still in doubt on {}", txn_id, name);
+                } else {
+                    tracing::info!("Rolling back transaction {} on {}", txn_id, name);
+                    resource.rollback(&txn_id)?;
+                }
+            }
+
+            // nothing committed is left prepared on the resource now, so it has applied
+            // every committed transaction it took part in
+            for (txn_id, participants, committed) in log.transactions() {
+                if committed && participants.iter().any(|participant| participant == name) {
+                    acknowledged
+                        .entry(txn_id)
+                        .or_default()
+                        .push(name.to_string());
+                }
+            }
+        }
+
+        for (txn_id, participants) in acknowledged {
+            log.acknowledge(&txn_id, &participants)?;
+        }
         Ok(())
     }
 
+    /// Manually decides the outcome of the in-doubt transaction `txn_id` and applies it to
+    /// `resources`. The in-doubt transactions are listed by [`TransactionLog::in_doubt`].
+    pub fn resolve(
+        log: &TransactionLog,
+        txn_id: &str,
+        commit: bool,
+        resources: &[(&str, Arc<dyn ResourceManager>)],
+    ) -> Result<()> {
+        if commit {
+            log.force_commit(txn_id)?;
+        } else {
+            log.force_abort(txn_id)?;
+        }
+        Self::recover(log, resources)
+    }
+
+    /// Commits every participant, preparing them first if that hasn't happened yet.
+    pub fn commit(&self) -> Result<()> {
+        self.core.commit()
+    }
+
+    /// Asks every participant to prepare, rolling back all of them if one fails.
     pub fn prepare(&self) -> Result<()> {
-        Ok(())
+        self.core.prepare()
+    }
+
+    pub fn abort(&self) -> Result<()> {
+        self.core.abort()
+    }
+}
+
+impl Drop for DistributedTransaction {
+    fn drop(&mut self) {
+        self.core.abort_unfinished("Distributed transaction");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::MSMQError;
+    use crate::message::Message;
+    use crate::queue::QueueOps;
+    use crate::queue_builder::QueueBuilder;
+    use crate::storage::{read_json, write_json};
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::sync::Mutex;
+
+    struct FailingResource;
+
+    /// Key-value store kept in a JSON file, with prepared writes kept in a second file until
+    /// they are committed.
+    struct FileStore {
+        path: PathBuf,
+        pending: Mutex<HashMap<String, HashMap<String, String>>>,
+    }
+
+    impl FileStore {
+        fn open(path: PathBuf) -> Self {
+            let pending = read_json(&path.with_extension("pending")).unwrap();
+            Self {
+                path,
+                pending: Mutex::new(pending),
+            }
+        }
+
+        fn put(&self, txn_id: &str, key: &str, value: &str) {
+            self.pending
+                .lock()
+                .unwrap()
+                .entry(txn_id.to_string())
+                .or_default()
+                .insert(key.to_string(), value.to_string());
+        }
+
+        fn get(&self, key: &str) -> Option<String> {
+            let data: HashMap<String, String> = read_json(&self.path).unwrap();
+            data.get(key).cloned()
+        }
+
+        fn save_pending(&self, pending: &HashMap<String, HashMap<String, String>>) -> Result<()> {
+            write_json(&self.path.with_extension("pending"), pending)
+        }
+    }
+
+    impl ResourceManager for FileStore {
+        fn prepare(&self, _txn_id: &str) -> Result<()> {
+            self.save_pending(&self.pending.lock().unwrap())
+        }
+
+        fn commit(&self, txn_id: &str) -> Result<()> {
+            let mut pending = self.pending.lock().unwrap();
+            if let Some(writes) = pending.remove(txn_id) {
+                let mut data: HashMap<String, String> = read_json(&self.path)?;
+                data.extend(writes);
+                write_json(&self.path, &data)?;
+            }
+            self.save_pending(&pending)
+        }
+
+        fn rollback(&self, txn_id: &str) -> Result<()> {
+            let mut pending = self.pending.lock().unwrap();
+            pending.remove(txn_id);
+            self.save_pending(&pending)
+        }
+
+        fn recover(&self) -> Result<Vec<String>> {
+            Ok(self.pending.lock().unwrap().keys().cloned().collect())
+        }
+    }
+
+    impl ResourceManager for FailingResource {
+        fn prepare(&self, _txn_id: &str) -> Result<()> {
+            Err(MSMQError::Custom("prepare failed".to_string()))
+        }
+
+        fn commit(&self, _txn_id: &str) -> Result<()> {
+            Ok(())
+        }
+
+        fn rollback(&self, _txn_id: &str) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_two_phase_commit_across_queues() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = Arc::new(TransactionLog::open(dir.path().join("dtc.log")).unwrap());
+        let mut orders = QueueBuilder::new("orders").with_transactional().build();
+        let mut invoices = QueueBuilder::new("invoices").with_transactional().build();
+
+        let dtx = DistributedTransaction::new().with_log(Arc::clone(&log));
+        orders
+            .send_distributed_transactional(Message::new("Order"), &dtx)
+            .unwrap();
+        invoices
+            .send_distributed_transactional(Message::new("Invoice"), &dtx)
+            .unwrap();
+
+        dtx.prepare().unwrap();
+        assert_eq!(dtx.state(), TransactionState::Prepared);
+        assert_eq!(orders.message_count().unwrap(), 0);
+
+        dtx.commit().unwrap();
+        assert_eq!(dtx.state(), TransactionState::Committed);
+        assert_eq!(orders.message_count().unwrap(), 1);
+        assert_eq!(invoices.message_count().unwrap(), 1);
+        assert!(!log.is_committed(dtx.id()));
+    }
+
+    #[test]
+    fn test_failed_prepare_rolls_back_every_participant() {
+        let mut orders = QueueBuilder::new("orders").with_transactional().build();
+
+        let dtx = DistributedTransaction::new();
+        orders
+            .send_distributed_transactional(Message::new("Order"), &dtx)
+            .unwrap();
+        dtx.enlist("failing", Arc::new(FailingResource)).unwrap();
+
+        assert!(dtx.commit().is_err());
+        assert_eq!(dtx.state(), TransactionState::Aborted);
+        assert_eq!(orders.message_count().unwrap(), 0);
+        assert!(orders.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_file_store_shares_outcome_with_queue() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = Arc::new(FileStore::open(dir.path().join("store.json")));
+        let mut orders = QueueBuilder::new("orders").with_transactional().build();
+
+        let dtx = DistributedTransaction::new();
+        orders
+            .send_distributed_transactional(Message::new("Order 1"), &dtx)
+            .unwrap();
+        store.put(dtx.id(), "order-1", "placed");
+        dtx.enlist("store", store.clone()).unwrap();
+        dtx.commit().unwrap();
+        assert_eq!(orders.message_count().unwrap(), 1);
+        assert_eq!(store.get("order-1").as_deref(), Some("placed"));
+
+        let dtx = DistributedTransaction::new();
+        orders
+            .send_distributed_transactional(Message::new("Order 2"), &dtx)
+            .unwrap();
+        store.put(dtx.id(), "order-2", "placed");
+        dtx.enlist("store", store.clone()).unwrap();
+        dtx.abort().unwrap();
+        assert_eq!(orders.message_count().unwrap(), 1);
+        assert_eq!(store.get("order-2"), None);
+    }
+
+    #[test]
+    fn test_recover_commits_logged_transaction_in_file_store() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = Arc::new(TransactionLog::open(dir.path().join("dtc.log")).unwrap());
+        let store_path = dir.path().join("store.json");
+        let build = || {
+            QueueBuilder::new("orders")
+                .with_transactional()
+                .with_persistence(dir.path().join("orders"))
+                .with_transaction_log(Arc::clone(&log))
+                .build()
+        };
+
+        let mut orders = build();
+        let store = Arc::new(FileStore::open(store_path.clone()));
+        let dtx = DistributedTransaction::new().with_log(Arc::clone(&log));
+        orders
+            .send_distributed_transactional(Message::new("Order"), &dtx)
+            .unwrap();
+        store.put(dtx.id(), "order", "placed");
+        dtx.enlist("store", store.clone()).unwrap();
+
+        // crash after the commit was recorded but before anything was applied
+        dtx.prepare().unwrap();
+        log.record_commit(dtx.id()).unwrap();
+        let txn_id = dtx.id().to_string();
+        std::mem::forget(dtx);
+        drop(store);
+
+        let mut orders = build();
+        let store: Arc<dyn ResourceManager> = Arc::new(FileStore::open(store_path.clone()));
+        assert_eq!(store.recover().unwrap().len(), 1);
+        DistributedTransaction::recover(&log, &[("store", Arc::clone(&store))]).unwrap();
+
+        assert!(store.recover().unwrap().is_empty());
+        assert!(!log.is_committed(&txn_id));
+        assert_eq!(
+            FileStore::open(store_path).get("order").as_deref(),
+            Some("placed")
+        );
+        assert_eq!(orders.receive().unwrap().content(), "Order");
+    }
+
+    #[test]
+    fn test_commit_record_outlives_participants_recovering_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = Arc::new(TransactionLog::open(dir.path().join("dtc.log")).unwrap());
+        let customers_path = dir.path().join("customers.json");
+        let accounts_path = dir.path().join("accounts.json");
+
+        let customers = Arc::new(FileStore::open(customers_path.clone()));
+        let accounts = Arc::new(FileStore::open(accounts_path.clone()));
+        let dtx = DistributedTransaction::new().with_log(Arc::clone(&log));
+        customers.put(dtx.id(), "alice", "registered");
+        accounts.put(dtx.id(), "alice", "opened");
+        dtx.enlist("customers", customers.clone()).unwrap();
+        dtx.enlist("accounts", accounts.clone()).unwrap();
+
+        // crash after the commit was recorded but before anything was applied
+        dtx.prepare().unwrap();
+        log.record_commit(dtx.id()).unwrap();
+        let txn_id = dtx.id().to_string();
+        std::mem::forget(dtx);
+        drop((customers, accounts));
+
+        let customers: Arc<dyn ResourceManager> = Arc::new(FileStore::open(customers_path.clone()));
+        DistributedTransaction::recover(&log, &[("customers", customers)]).unwrap();
+        assert!(log.is_committed(&txn_id));
+
+        let accounts: Arc<dyn ResourceManager> = Arc::new(FileStore::open(accounts_path.clone()));
+        DistributedTransaction::recover(&log, &[("accounts", accounts)]).unwrap();
+        assert!(!log.is_committed(&txn_id));
+        assert_eq!(
+            FileStore::open(customers_path).get("alice").as_deref(),
+            Some("registered")
+        );
+        assert_eq!(
+            FileStore::open(accounts_path).get("alice").as_deref(),
+            Some("opened")
+        );
+    }
+
+    #[test]
+    fn test_restart_presumes_undecided_transaction_aborted() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("dtc.log");
+        let build = |log: &Arc<TransactionLog>| {
+            QueueBuilder::new("orders")
+                .with_transactional()
+                .with_persistence(dir.path().join("orders"))
+                .with_transaction_log(Arc::clone(log))
+                .build()
+        };
+
+        let log = Arc::new(TransactionLog::open(&log_path).unwrap());
+        let mut orders = build(&log);
+        let dtx = DistributedTransaction::new().with_log(Arc::clone(&log));
+        orders
+            .send_distributed_transactional(Message::new("Order"), &dtx)
+            .unwrap();
+
+        // the coordinator crashes after prepare, before deciding
+        dtx.prepare().unwrap();
+        std::mem::forget(dtx);
+
+        let log = Arc::new(TransactionLog::open(&log_path).unwrap());
+        let orders = build(&log);
+        assert!(log.in_doubt().is_empty());
+        assert!(log.transactions().is_empty());
+        assert!(orders.recover().unwrap().is_empty());
+        assert_eq!(orders.message_count().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_in_doubt_branch_waits_for_manual_resolution() {
+        let dir = tempfile::tempdir().unwrap();
+        let log_path = dir.path().join("dtc.log");
+        let store_path = dir.path().join("store.json");
+        let build = |log: &Arc<TransactionLog>| {
+            QueueBuilder::new("orders")
+                .with_transactional()
+                .with_persistence(dir.path().join("orders"))
+                .with_transaction_log(Arc::clone(log))
+                .build()
+        };
+
+        let log = Arc::new(TransactionLog::open(&log_path).unwrap());
+        let mut orders = build(&log);
+        let store = Arc::new(FileStore::open(store_path.clone()));
+        let branch = DistributedTransaction::branch("txn-1").with_log(Arc::clone(&log));
+        orders
+            .send_distributed_transactional(Message::new("Order"), &branch)
+            .unwrap();
+        store.put(branch.id(), "order", "placed");
+        branch.enlist("store", store.clone()).unwrap();
+
+        // the coordinator disappears after the branch prepared
+        branch.prepare().unwrap();
+        std::mem::forget(branch);
+        drop(store);
+
+        let log = Arc::new(TransactionLog::open(&log_path).unwrap());
+        let mut orders = build(&log);
+        let store: Arc<dyn ResourceManager> = Arc::new(FileStore::open(store_path.clone()));
+        let resources: [(&str, Arc<dyn ResourceManager>); 2] = [
+            ("orders", Arc::new(orders.clone())),
+            ("store", Arc::clone(&store)),
+        ];
+        DistributedTransaction::recover(&log, &resources).unwrap();
+        assert_eq!(orders.message_count().unwrap(), 0);
+        assert_eq!(resources[0].1.recover().unwrap(), vec!["txn-1".to_string()]);
+        let in_doubt = log.in_doubt();
+        assert_eq!(in_doubt.len(), 1);
+        assert_eq!(in_doubt[0].id, "txn-1");
+
+        // resolving with only some of the participants keeps the decision for the others
+        DistributedTransaction::resolve(&log, "txn-1", true, &resources[..1]).unwrap();
+        assert!(log.in_doubt().is_empty());
+        assert!(log.is_committed("txn-1"));
+        assert_eq!(orders.receive().unwrap().content(), "Order");
+
+        DistributedTransaction::recover(&log, &resources[1..]).unwrap();
+        assert!(!log.is_committed("txn-1"));
+        assert_eq!(
+            FileStore::open(store_path).get("order").as_deref(),
+            Some("placed")
+        );
     }
 }
@@ -14,10 +14,36 @@ pub enum MSMQError {
     Serde(SerdeError),
     #[error("Couldn't find queue {0}")]
     QueueNotFound(String),
+    #[error("The name {0} is already taken by something else")]
+    NameInUse(String),
+    #[error("Destination {0} refers back to itself")]
+    DestinationLoop(String),
+    #[error("No route for message {0}")]
+    Unroutable(String),
     #[error("Transaction could not be found")]
     TransactionNotFound,
+    #[error("Transaction {0} is no longer active")]
+    TransactionNotActive(String),
+    #[error("Transaction {0} timed out and was aborted")]
+    TransactionTimedOut(String),
+    #[error("Queue {0} is transactional and only accepts sends within a transaction")]
+    TransactionRequired(String),
+    #[error("Queue {0} is not transactional and can't take part in a transaction")]
+    NotTransactional(String),
     #[error("Unknown command format")]
     CommandFormatError,
+    #[error("Invalid selector: {0}")]
+    InvalidSelector(String),
+    #[error("Invalid topic: {0}")]
+    InvalidTopic(String),
+    #[error("No leased message with id {0}")]
+    LeaseNotFound(String),
+    #[error("Session {0} is locked by another consumer")]
+    SessionLocked(String),
+    #[error("Couldn't find message {0}")]
+    MessageNotFound(String),
+    #[error("Invalid format name {0}, expected queue;subqueue")]
+    InvalidFormatName(String),
 }
 
 impl From<String> for MSMQError {
@@ -5,40 +5,99 @@ use crate::queue::QueueOps;
 use crate::{
     message::Message,
     queue::{BasicQueue, Queue},
+    storage::JournalRecord,
     Result,
 };
 
-pub trait DeadLetterFeature: Send + Sync {}
+pub trait DeadLetterFeature: Send + Sync {
+    /// Number of failed deliveries a message may have before it is dead-lettered.
+    /// `None` keeps redelivering it indefinitely.
+    fn max_retries(&self) -> Option<u32> {
+        None
+    }
 
-#[derive(Default, Clone)]
-pub struct DeadletterQueue<E>(BasicQueue<Message<E>>);
+    fn dead_letter(&self, _message: Message) {
+        // no-op
+    }
+
+    /// Copies of the dead-lettered messages, for persisting them.
+    fn dead_lettered(&self) -> Vec<Message> {
+        Vec::new()
+    }
+
+    /// Puts back messages that were dead-lettered before a restart.
+    fn restore(&self, _messages: Vec<Message>) {
+        // no-op
+    }
+}
+
+#[derive(Clone)]
+pub struct DeadletterQueue<E> {
+    pub(crate) messages: BasicQueue<Message<E>>,
+    pub(crate) max_retries: Option<u32>,
+}
+
+impl<E> Default for DeadletterQueue<E> {
+    fn default() -> Self {
+        Self {
+            messages: Default::default(),
+            max_retries: None,
+        }
+    }
+}
 
 #[derive(Default, Clone)]
 pub struct EmptyDeadletterQueue;
 
-impl<E: EncryptFeature> DeadLetterFeature for DeadletterQueue<E> {}
+impl<E: EncryptFeature> DeadLetterFeature for DeadletterQueue<E> {
+    fn max_retries(&self) -> Option<u32> {
+        self.max_retries
+    }
+
+    fn dead_letter(&self, message: Message) {
+        self.messages
+            .lock()
+            .expect("Couldnt lock queue")
+            .push_back(message.cast());
+    }
+
+    fn dead_lettered(&self) -> Vec<Message> {
+        self.messages
+            .lock()
+            .expect("Couldnt lock queue")
+            .iter()
+            .map(|message| message.clone().cast())
+            .collect()
+    }
+
+    fn restore(&self, messages: Vec<Message>) {
+        self.messages
+            .lock()
+            .expect("Couldnt lock queue")
+            .extend(messages.into_iter().map(|message| message.cast()));
+    }
+}
+
 impl DeadLetterFeature for EmptyDeadletterQueue {}
 
 impl<J, T, E> Queue<J, T, E, DeadletterQueue<E>>
 where
-    J: JournalFeature,
-    T: TransactionalFeature,
-    E: EncryptFeature,
+    J: JournalFeature + Clone + 'static,
+    T: TransactionalFeature + Clone + 'static,
+    E: EncryptFeature + Clone,
 {
     pub fn move_to_dlq(&mut self) -> Result<()> {
         if let Some(message) = self.receive() {
-            self.dlq
-                .0
-                .lock()
-                .expect("Couldnt lock queue")
-                .push_back(message);
+            let mut messages = self.dlq.messages.lock().expect("Couldnt lock queue");
+            self.record(|| JournalRecord::DeadLettered(message.clone()));
+            messages.push_back(message);
         }
 
-        Ok(())
+        self.persist()
     }
 
     pub fn dlq_count(&self) -> usize {
-        self.dlq.0.lock().expect("Couldnt lock queue").len()
+        self.dlq.messages.lock().expect("Couldnt lock queue").len()
     }
 }
 
@@ -46,10 +105,11 @@ where
 mod tests {
     use super::*;
     use crate::{message::Message, queue_builder::QueueBuilder};
+    use std::time::Duration;
 
     #[test]
     fn test_dead_letter_queue() {
-        let mut queue = QueueBuilder::new("test_queue").with_dlq().build();
+        let mut queue = QueueBuilder::new("test_queue").with_dlq(None).build();
 
         let message = Message::new("Undeliverable message");
         queue.send(message).unwrap();
@@ -58,4 +118,68 @@ mod tests {
         assert_eq!(queue.message_count().unwrap(), 0);
         assert_eq!(queue.dlq_count(), 1);
     }
+
+    #[test]
+    fn test_poison_message_moves_to_dlq_after_max_retries() {
+        let mut queue = QueueBuilder::new("test_queue").with_dlq(Some(2)).build();
+        queue.send(Message::new("Poison message")).unwrap();
+
+        for attempt in 1..=2 {
+            let message = queue.lease(Duration::from_secs(30)).unwrap();
+            queue.nack(message.id()).unwrap();
+            assert_eq!(queue.message_count().unwrap(), 1);
+            assert_eq!(
+                queue.peek_where(|_| true).unwrap().delivery_count(),
+                attempt
+            );
+        }
+
+        // the lease on the third attempt expires instead of being nacked
+        queue.lease(Duration::ZERO).unwrap();
+        queue.reclaim_expired_leases();
+
+        assert_eq!(queue.message_count().unwrap(), 0);
+        assert_eq!(queue.dlq_count(), 1);
+    }
+
+    #[test]
+    fn test_receive_reclaims_expired_leases() {
+        let mut queue = QueueBuilder::new("test_queue").with_dlq(Some(1)).build();
+        queue.send(Message::new("Leased message")).unwrap();
+
+        queue.lease(Duration::ZERO).unwrap();
+        let message = queue.receive().unwrap();
+        assert_eq!(message.delivery_count(), 1);
+
+        queue.send(message).unwrap();
+        queue.lease(Duration::from_millis(20)).unwrap();
+        assert!(queue
+            .receive_batch(1, Duration::from_millis(500))
+            .is_empty());
+        assert_eq!(queue.dlq_count(), 1);
+    }
+
+    #[test]
+    fn test_dead_lettered_messages_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("orders.msmq");
+        {
+            let mut queue = QueueBuilder::new("orders")
+                .with_dlq(Some(0))
+                .with_persistence(&path)
+                .build();
+            queue.send(Message::new("Poison message")).unwrap();
+            queue.send(Message::new("Good message")).unwrap();
+            let message = queue.lease(Duration::from_secs(30)).unwrap();
+            queue.nack(message.id()).unwrap();
+        }
+
+        let mut queue = QueueBuilder::new("orders")
+            .with_dlq(Some(0))
+            .with_persistence(&path)
+            .build();
+        assert_eq!(queue.dlq_count(), 1);
+        assert_eq!(queue.receive().unwrap().content(), "Good message");
+        assert!(queue.receive().is_none());
+    }
 }
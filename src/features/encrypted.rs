@@ -5,6 +5,7 @@ use crate::message::Message;
 use crate::queue::Queue;
 use crate::queue::QueueOps;
 use crate::security::Security;
+use crate::storage::JournalRecord;
 use crate::{MSMQError, Result};
 
 pub trait EncryptFeature: Send + Sync + 'static {}
@@ -20,16 +21,22 @@ impl EncryptFeature for AnonymousEncryption {}
 
 impl<J, T, D> Queue<J, T, BasicEncryption, D>
 where
-    J: JournalFeature,
-    T: TransactionalFeature,
-    D: DeadLetterFeature,
+    J: JournalFeature + Clone + 'static,
+    T: TransactionalFeature + Clone + 'static,
+    D: DeadLetterFeature + Clone + 'static,
 {
     pub fn send_authenticated(&mut self, message: Message<BasicEncryption>) -> Result<()> {
-        self.queue
-            .lock()
-            .map_err(|e| MSMQError::Custom(e.to_string()))?
-            .push_back(message);
-        Ok(())
+        self.check_transactional(false)?;
+        {
+            let mut queue = self
+                .queue
+                .lock()
+                .map_err(|e| MSMQError::Custom(e.to_string()))?;
+            self.record(|| JournalRecord::Enqueued(vec![message.clone()]));
+            queue.push_back(message);
+        }
+        self.available.notify_all();
+        self.persist()
     }
 
     pub fn receive_authenticated(
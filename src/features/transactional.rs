@@ -1,13 +1,26 @@
 use lazy_static::lazy_static;
 
 use super::{DeadLetterFeature, EncryptFeature, JournalFeature};
+use crate::distributed_transaction::DistributedTransaction;
+use crate::error::MSMQError;
+use crate::queue::is_receivable;
+use crate::sequence::{self, Sequences};
+use crate::storage::JournalRecord;
+use crate::transaction::ResourceManager;
+use crate::transaction_log::TransactionLog;
 use crate::Result;
 use crate::{message::Message, queue::Queue, transaction::Transaction};
+use serde::{Deserialize, Serialize};
 use std::any::Any;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
-pub trait TransactionalFeature: Send + Sync {}
+pub trait TransactionalFeature: Send + Sync {
+    /// Whether sends have to go through a transaction. Transactional queues reject plain
+    /// sends, and every other queue rejects transactional ones.
+    const TRANSACTIONAL: bool = false;
+}
 
 #[derive(Default, Clone)]
 pub struct TransactionalQueue;
@@ -15,31 +28,307 @@ pub struct TransactionalQueue;
 #[derive(Default, Clone)]
 pub struct EmptyTransactionalQueue;
 
-impl TransactionalFeature for TransactionalQueue {}
+impl TransactionalFeature for TransactionalQueue {
+    const TRANSACTIONAL: bool = true;
+}
 impl TransactionalFeature for EmptyTransactionalQueue {}
 
+/// What a transaction has done to a queue so far.
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(crate) struct PendingWork<E> {
+    pub sends: Vec<Message<E>>,
+    /// Received messages together with the position they were taken from.
+    pub receives: Vec<(usize, Message<E>)>,
+    /// Whether the work has been persisted by [`ResourceManager::prepare`].
+    #[serde(skip)]
+    pub prepared: bool,
+}
+
+impl<E> Default for PendingWork<E> {
+    fn default() -> Self {
+        Self {
+            sends: Vec::new(),
+            receives: Vec::new(),
+            prepared: false,
+        }
+    }
+}
+
+impl<E> Clone for PendingWork<E> {
+    fn clone(&self) -> Self {
+        Self {
+            sends: self.sends.clone(),
+            receives: self.receives.clone(),
+            prepared: self.prepared,
+        }
+    }
+}
+
+impl<E> PendingWork<E> {
+    /// Delivers the sends, in order for senders that number their messages; the received
+    /// messages stay removed.
+    pub fn apply(self, queue: &mut VecDeque<Message<E>>, sequences: &mut Sequences<E>) {
+        for message in self.sends {
+            sequence::deliver(message, queue, sequences);
+        }
+    }
+
+    /// Drops the sends and puts every received message back where it was taken from,
+    /// returning copies of them as they were put back.
+    pub fn undo(self, queue: &mut VecDeque<Message<E>>) -> Vec<(usize, Message<E>)> {
+        let mut restored = Vec::with_capacity(self.receives.len());
+        // newest first, so every position refers to the queue as it was when that message
+        // was taken
+        for (position, mut message) in self.receives.into_iter().rev() {
+            message.record_abort();
+            restored.push((position, message.clone()));
+            let position = position.min(queue.len());
+            queue.insert(position, message);
+        }
+        restored.reverse();
+        restored
+    }
+}
+
+impl<J, T, E, D> Queue<J, T, E, D>
+where
+    T: TransactionalFeature,
+    E: EncryptFeature,
+    D: DeadLetterFeature,
+{
+    /// Fails unless a send that is `transactional` or not matches the kind of queue.
+    pub(crate) fn check_transactional(&self, transactional: bool) -> Result<()> {
+        match (T::TRANSACTIONAL, transactional) {
+            (true, false) => Err(MSMQError::TransactionRequired(self.name.clone())),
+            (false, true) => Err(MSMQError::NotTransactional(self.name.clone())),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl<J, T, E, D> Queue<J, T, E, D>
+where
+    J: JournalFeature + Clone + 'static,
+    T: TransactionalFeature + Clone + 'static,
+    E: EncryptFeature + Clone,
+    D: DeadLetterFeature + Clone + 'static,
+{
+    /// Receives the next message under `distributed_transaction`, enlisting the queue as one
+    /// of its participants. The message goes back to its original position on abort.
+    pub fn receive_distributed_transactional(
+        &self,
+        distributed_transaction: &DistributedTransaction,
+    ) -> Result<Option<Message<E>>> {
+        self.check_transactional(true)?;
+        if let Some(timeout) = self.transaction_timeout {
+            distributed_transaction.set_default_timeout(timeout);
+        }
+        self.reclaim_expired_leases();
+        self.expire_held_sequences();
+        distributed_transaction.enlist_with(&self.name, Arc::new(self.clone()), || {
+            self.receive_pending(distributed_transaction.id())
+        })
+    }
+
+    /// Takes the next receivable message, remembering it as received by `txn_id`.
+    fn receive_pending(&self, txn_id: &str) -> Result<Option<Message<E>>> {
+        // pending work is locked before the queue, as in persist
+        let mut pending = self
+            .pending
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?;
+        let now = SystemTime::now();
+        let mut queue = self
+            .queue
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?;
+        let Some(position) = queue.iter().position(|message| is_receivable(message, now)) else {
+            return Ok(None);
+        };
+        let message = queue.remove(position).expect("position is in bounds");
+
+        pending
+            .entry(txn_id.to_string())
+            .or_default()
+            .receives
+            .push((position, message.clone()));
+        Ok(Some(message))
+    }
+}
+
 impl<J, E, D> Queue<J, TransactionalQueue, E, D>
+where
+    J: JournalFeature + Clone + 'static,
+    E: EncryptFeature + Clone,
+    D: DeadLetterFeature + Clone + 'static,
+{
+    /// Buffers `message` until `txn` commits.
+    pub fn send_transactional(&self, message: Message<E>, txn: &Transaction<E>) -> Result<()> {
+        if let Some(timeout) = self.transaction_timeout {
+            txn.set_default_timeout(timeout);
+        }
+        txn.enlist_with(&self.name, Arc::new(self.clone()), || {
+            self.pending
+                .lock()
+                .map_err(|e| MSMQError::Custom(e.to_string()))?
+                .entry(txn.id().to_string())
+                .or_default()
+                .sends
+                .push(message);
+            Ok(())
+        })
+    }
+
+    /// Receives the next message under `txn`. It is only removed for good when `txn`
+    /// commits; on abort it goes back to its original position.
+    pub fn receive_transactional(&self, txn: &Transaction<E>) -> Result<Option<Message<E>>> {
+        if let Some(timeout) = self.transaction_timeout {
+            txn.set_default_timeout(timeout);
+        }
+        self.reclaim_expired_leases();
+        self.expire_held_sequences();
+        txn.enlist_with(&self.name, Arc::new(self.clone()), || {
+            self.receive_pending(txn.id())
+        })
+    }
+}
+
+impl<J, T, E, D> ResourceManager for Queue<J, T, E, D>
 where
     J: JournalFeature,
+    T: TransactionalFeature,
     E: EncryptFeature,
     D: DeadLetterFeature,
 {
-    pub fn send_transactional(&self, message: Message<E>, txn: &Transaction<E>) -> Result<()> {
-        txn.operations
+    fn prepare(&self, txn_id: &str) -> Result<()> {
+        let mut pending = self
+            .pending
             .lock()
-            .unwrap()
-            .entry(self.name.clone())
-            .or_default()
-            .push(message);
-        Ok(())
+            .map_err(|e| MSMQError::Custom(e.to_string()))?;
+        if let Some(work) = pending.get_mut(txn_id) {
+            work.prepared = true;
+            self.record(|| JournalRecord::Prepared {
+                txn_id: txn_id.to_string(),
+                work: work.clone(),
+            });
+        }
+        drop(pending);
+
+        self.persist()
+    }
+
+    fn commit(&self, txn_id: &str) -> Result<()> {
+        let Some(work) = self
+            .pending
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?
+            .remove(txn_id)
+        else {
+            return Ok(());
+        };
+
+        for (_, message) in &work.receives {
+            self.journaled_queue
+                .append_journal_messages(message.content());
+        }
+        {
+            let mut queue = self
+                .queue
+                .lock()
+                .map_err(|e| MSMQError::Custom(e.to_string()))?;
+            let mut sequences = self
+                .sequences
+                .lock()
+                .map_err(|e| MSMQError::Custom(e.to_string()))?;
+            let received = work
+                .receives
+                .iter()
+                .map(|(_, message)| message.id().to_string())
+                .collect();
+            let senders: Vec<String> = work
+                .sends
+                .iter()
+                .filter_map(|message| message.sender())
+                .map(str::to_string)
+                .collect();
+            let queued = queue.len();
+            work.apply(&mut queue, &mut sequences);
+            self.record(|| JournalRecord::Committed {
+                txn_id: txn_id.to_string(),
+                received,
+                delivered: queue.range(queued..).cloned().collect(),
+                sequences: senders
+                    .into_iter()
+                    .filter_map(|sender| {
+                        let sequence = sequences.get(&sender)?.clone();
+                        Some((sender, sequence))
+                    })
+                    .collect(),
+            });
+        }
+        self.available.notify_all();
+
+        self.persist()
+    }
+
+    fn rollback(&self, txn_id: &str) -> Result<()> {
+        let Some(work) = self
+            .pending
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?
+            .remove(txn_id)
+        else {
+            return Ok(());
+        };
+
+        if work.receives.is_empty() && !work.prepared {
+            return Ok(());
+        }
+        {
+            let mut queue = self
+                .queue
+                .lock()
+                .map_err(|e| MSMQError::Custom(e.to_string()))?;
+            let restored = work.undo(&mut queue);
+            self.record(|| JournalRecord::RolledBack {
+                txn_id: txn_id.to_string(),
+                restored,
+            });
+        }
+        self.available.notify_all();
+
+        self.persist()
+    }
+
+    fn recover(&self) -> Result<Vec<String>> {
+        Ok(self
+            .pending
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?
+            .iter()
+            .filter(|(_, work)| work.prepared)
+            .map(|(txn_id, _)| txn_id.clone())
+            .collect())
+    }
+
+    /// Clones of a queue share their state, so they are the same resource.
+    fn resource_id(&self) -> Option<usize> {
+        Some(Arc::as_ptr(&self.queue) as usize)
+    }
+
+    fn transaction_log(&self) -> Option<Arc<TransactionLog>> {
+        self.transaction_log.clone()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::distributed_transaction::DistributedTransaction;
     use crate::queue::QueueOps;
     use crate::{message::Message, queue_builder::QueueBuilder, transaction::Transaction};
+    use std::time::Duration;
 
     #[test]
     fn test_transactional_operations() {
@@ -53,4 +342,169 @@ mod tests {
         txn.commit().unwrap();
         assert_eq!(queue.message_count().unwrap(), 1);
     }
+
+    #[test]
+    fn test_commit_delivers_to_every_queue() {
+        let orders = QueueBuilder::new("orders").with_transactional().build();
+        let invoices = QueueBuilder::new("invoices").with_transactional().build();
+
+        let txn = Transaction::new();
+        orders
+            .send_transactional(Message::new("Order 1"), &txn)
+            .unwrap();
+        orders
+            .send_transactional(Message::new("Order 2"), &txn)
+            .unwrap();
+        invoices
+            .send_transactional(Message::new("Invoice"), &txn)
+            .unwrap();
+        assert_eq!(orders.message_count().unwrap(), 0);
+        assert_eq!(invoices.message_count().unwrap(), 0);
+
+        txn.commit().unwrap();
+        assert_eq!(orders.message_count().unwrap(), 2);
+        assert_eq!(invoices.message_count().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_receive_transactional_restores_position_on_abort() {
+        let mut queue = QueueBuilder::new("test_queue").with_transactional().build();
+        let seed = Transaction::new();
+        for content in ["First", "Second", "Third"] {
+            queue
+                .send_transactional(Message::new(content), &seed)
+                .unwrap();
+        }
+        seed.commit().unwrap();
+
+        let txn = Transaction::new();
+        let first = queue.receive_transactional(&txn).unwrap().unwrap();
+        let second = queue.receive_transactional(&txn).unwrap().unwrap();
+        assert_eq!(first.content(), "First");
+        assert_eq!(second.content(), "Second");
+        assert_eq!(queue.message_count().unwrap(), 1);
+
+        txn.abort().unwrap();
+        assert_eq!(queue.message_count().unwrap(), 3);
+        let first = queue.receive().unwrap();
+        assert_eq!(first.content(), "First");
+        assert_eq!(first.abort_count(), 1);
+        assert_eq!(queue.receive().unwrap().content(), "Second");
+
+        let txn = Transaction::new();
+        let third = queue.receive_transactional(&txn).unwrap().unwrap();
+        assert_eq!(third.content(), "Third");
+        txn.commit().unwrap();
+        assert_eq!(queue.message_count().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_sends_must_match_queue_kind() {
+        let mut transactional = QueueBuilder::new("orders").with_transactional().build();
+        let mut plain = QueueBuilder::new("invoices").build();
+
+        assert!(matches!(
+            transactional.send(Message::new("Order")),
+            Err(MSMQError::TransactionRequired(_))
+        ));
+        assert!(matches!(
+            transactional.send_batch(vec![Message::new("Order")]),
+            Err(MSMQError::TransactionRequired(_))
+        ));
+
+        let dtx = DistributedTransaction::new();
+        assert!(matches!(
+            plain.send_distributed_transactional(Message::new("Invoice"), &dtx),
+            Err(MSMQError::NotTransactional(_))
+        ));
+        transactional
+            .send_distributed_transactional(Message::new("Order"), &dtx)
+            .unwrap();
+        dtx.commit().unwrap();
+        assert_eq!(transactional.message_count().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_sequenced_sends_survive_restart_while_held() {
+        let dir = tempfile::tempdir().unwrap();
+        let build = || {
+            QueueBuilder::new("orders")
+                .with_transactional()
+                .with_persistence(dir.path().join("orders"))
+                .build()
+        };
+        let send = |queue: &Queue<_, TransactionalQueue>, number| {
+            let txn = Transaction::new();
+            let message = Message::new(&format!("Order {}", number)).with_sequence("shop", number);
+            queue.send_transactional(message, &txn).unwrap();
+            txn.commit().unwrap();
+        };
+
+        let queue = build();
+        send(&queue, 2);
+        assert_eq!(queue.message_count().unwrap(), 0);
+
+        let mut queue = build();
+        send(&queue, 1);
+        send(&queue, 1);
+        assert_eq!(queue.receive().unwrap().content(), "Order 1");
+        assert_eq!(queue.receive().unwrap().content(), "Order 2");
+        assert!(queue.receive().is_none());
+    }
+
+    #[test]
+    fn test_sends_are_numbered_per_sender_as_they_commit() {
+        let mut queue = QueueBuilder::new("orders").with_transactional().build();
+        let first = Transaction::new();
+        let second = Transaction::new();
+        queue
+            .send_transactional(Message::new("Order A").with_sender("shop"), &first)
+            .unwrap();
+        queue
+            .send_transactional(Message::new("Order B").with_sender("shop"), &second)
+            .unwrap();
+        queue
+            .send_transactional(Message::new("Invoice").with_sender("billing"), &second)
+            .unwrap();
+        second.commit().unwrap();
+        first.commit().unwrap();
+
+        let received: Vec<_> = std::iter::from_fn(|| queue.receive())
+            .map(|message| {
+                let (sender, number) = message.sequence().unwrap();
+                (message.content().clone(), sender.to_string(), number)
+            })
+            .collect();
+        assert_eq!(
+            received,
+            [
+                ("Order B".to_string(), "shop".to_string(), 1),
+                ("Invoice".to_string(), "billing".to_string(), 1),
+                ("Order A".to_string(), "shop".to_string(), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_held_messages_are_released_after_hold_timeout() {
+        let mut queue = QueueBuilder::new("orders")
+            .with_transactional()
+            .with_sequence_hold_timeout(Duration::from_millis(50))
+            .build();
+        let send = |queue: &Queue<_, TransactionalQueue>, number| {
+            let txn = Transaction::new();
+            let message = Message::new(&format!("Order {}", number)).with_sequence("shop", number);
+            queue.send_transactional(message, &txn).unwrap();
+            txn.commit().unwrap();
+        };
+
+        send(&queue, 2);
+        assert!(queue.receive().is_none());
+        std::thread::sleep(Duration::from_millis(100));
+        assert_eq!(queue.receive().unwrap().content(), "Order 2");
+
+        // the message given up on arrives too late
+        send(&queue, 1);
+        assert!(queue.receive().is_none());
+    }
 }
@@ -1,48 +1,285 @@
 #![allow(unused)]
 
+pub mod coordinator;
+mod dedup;
+pub mod directory;
 pub mod distributed_transaction;
 mod error;
 pub mod features;
 pub mod message;
 pub mod multicast_group;
+mod multicast_transport;
+pub mod outgoing;
 pub mod queue;
 pub mod queue_builder;
+pub mod router;
 pub mod security;
+pub mod selector;
+mod sequence;
+pub mod session;
+mod storage;
+pub mod subqueue;
+pub mod topic;
 pub mod transaction;
+pub mod transaction_log;
 
-use crate::queue::QueueOps;
+use crate::directory::Directory;
+use crate::distributed_transaction::DistributedTransaction;
+use crate::outgoing::{OutgoingQueues, OutgoingStatus};
+use crate::queue::{QueueOps, SendOutcome};
+use crate::router::Router;
+use crate::transaction::{ResourceManager, TransactionState};
+use crate::transaction_log::{InDoubtTransaction, TransactionLog};
 use error::{MSMQError, Result};
+use features::{
+    AnonymousEncryption, EmptyJournal, EmptyTransactionalQueue, TransactionalFeature,
+    TransactionalQueue,
+};
 use message::Message;
 use queue::Queue;
 use queue_builder::QueueBuilder;
+use selector::Selector;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::io::{Read, Write};
 use std::net::{TcpListener, TcpStream};
 use std::sync::{Arc, Mutex};
 use std::thread;
+use std::time::Duration;
 
 #[derive(Serialize, Deserialize, Debug)]
 enum ReceivedMessage {
-    Enqueue { content: String },
+    Enqueue {
+        content: String,
+    },
+    EnqueueMessage {
+        message: Message<AnonymousEncryption>,
+    },
+    EnqueueBatch {
+        contents: Vec<String>,
+    },
+    /// Sends `message` in a transaction of its own, for transactional queues.
+    EnqueueTransactional {
+        message: Message<AnonymousEncryption>,
+    },
     Dequeue,
+    /// Starts a transaction that lives until it is committed or aborted, or the connection
+    /// closes.
+    BeginTransaction,
+    Commit {
+        transaction_id: String,
+    },
+    Abort {
+        transaction_id: String,
+    },
+    EnqueueInTransaction {
+        transaction_id: String,
+        content: String,
+    },
+    DequeueInTransaction {
+        transaction_id: String,
+    },
+    /// Starts the local branch of a transaction driven by a coordinator.
+    JoinTransaction {
+        transaction_id: String,
+    },
+    /// Asks for a coordinator's vote: `Success` once the branch durably prepared.
+    Prepare {
+        transaction_id: String,
+    },
+    CommitPrepared {
+        transaction_id: String,
+    },
+    AbortPrepared {
+        transaction_id: String,
+    },
+    DequeueWhere {
+        selector: String,
+    },
+    DequeueBatch {
+        max: usize,
+        timeout_ms: u64,
+    },
+    /// Subqueues are addressed by format name, like `orders;poison`.
+    MoveToSubqueue {
+        id: String,
+        format_name: String,
+    },
+    DequeueSubqueue {
+        format_name: String,
+    },
+    PeekSubqueue {
+        format_name: String,
+    },
+    /// Sends `message` to the queues the queue name, alias or distribution list `destination`
+    /// stands for.
+    SendTo {
+        destination: String,
+        message: Message<AnonymousEncryption>,
+    },
+    SetAlias {
+        alias: String,
+        target: String,
+    },
+    DefineDistributionList {
+        name: String,
+        members: Vec<String>,
+    },
+    /// Removes an alias, distribution list or remote destination.
+    RemoveDestination {
+        name: String,
+    },
+    /// Makes the queue server listening on `address` a destination named `name`, which
+    /// aliases, distribution lists and routes can point at.
+    RegisterRemoteDestination {
+        name: String,
+        address: String,
+    },
+    /// Sends `message` to the destination picked by the server's routing rules.
+    Route {
+        message: Message<AnonymousEncryption>,
+    },
+    /// Reads the server's routes file again.
+    ReloadRoutes,
+    /// Stores `message` to be forwarded to the queue server listening on `address`.
+    SendRemote {
+        address: String,
+        message: Message<AnonymousEncryption>,
+    },
+    ListOutgoing,
+    PauseOutgoing {
+        address: String,
+    },
+    ResumeOutgoing {
+        address: String,
+    },
+    ListInDoubtTransactions,
+    /// Manually commits or aborts an in-doubt transaction.
+    ResolveTransaction {
+        id: String,
+        commit: bool,
+    },
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 enum Response {
     Success,
-    Error { message: String },
-    Dequeued { content: String },
+    Error {
+        message: String,
+    },
+    /// The message was refused for good, e.g. by a transactional queue; sending it again
+    /// won't help.
+    Rejected {
+        message: String,
+    },
+    Dequeued {
+        content: String,
+    },
+    Peeked {
+        content: String,
+    },
+    /// The message was already sent within the queue's deduplication window and was dropped.
+    Duplicate {
+        id: String,
+    },
+    DequeuedBatch {
+        contents: Vec<String>,
+    },
+    InDoubtTransactions {
+        transactions: Vec<InDoubtTransaction>,
+    },
+    TransactionStarted {
+        transaction_id: String,
+    },
+    Routed {
+        destination: String,
+    },
+    OutgoingQueues {
+        queues: Vec<OutgoingStatus>,
+    },
 }
 
-struct QueueServer {
-    queue: Arc<Mutex<Queue>>,
+/// How long the server remembers message ids, so client retries aren't enqueued twice.
+const DEDUPLICATION_WINDOW: Duration = Duration::from_secs(5 * 60);
+/// How long a branch may stay unprepared before it is aborted, in case its coordinator died.
+const BRANCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Local branches of transactions driven by a [`Coordinator`](coordinator::Coordinator).
+struct Branches {
+    /// Branches keyed by transaction id.
+    open: Mutex<HashMap<String, Arc<DistributedTransaction>>>,
+    /// How long a branch may stay unprepared before it is aborted.
+    timeout: Duration,
+}
+
+impl Branches {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            open: Mutex::new(HashMap::new()),
+            timeout,
+        }
+    }
+}
+
+struct QueueServer<T = EmptyTransactionalQueue> {
+    queue: Arc<Mutex<Queue<EmptyJournal, T>>>,
+    /// Coordinator state of the transactions the queue takes part in, kept next to the queue.
+    log: Arc<TransactionLog>,
+    branches: Arc<Branches>,
+    /// Aliases and distribution lists, with the queue registered under its file stem.
+    directory: Directory,
+    /// Routing rules kept next to the queue, applied to `Route` requests.
+    router: Router,
+    /// Messages waiting to be forwarded to other servers.
+    outgoing: Arc<OutgoingQueues>,
 }
 
 impl QueueServer {
     fn new(queue_path: &str) -> Result<Self> {
-        let queue = QueueBuilder::new(queue_path).build();
+        Self::open(queue_path, QueueBuilder::new(queue_path))
+    }
+}
+
+impl QueueServer<TransactionalQueue> {
+    /// Serves a transactional queue, which rejects sends made outside a transaction.
+    fn transactional(queue_path: &str) -> Result<Self> {
+        Self::open(
+            queue_path,
+            QueueBuilder::new(queue_path).with_transactional(),
+        )
+    }
+}
+
+impl<T> QueueServer<T>
+where
+    T: TransactionalFeature + Clone + 'static,
+{
+    fn open(queue_path: &str, builder: QueueBuilder<EmptyJournal, T>) -> Result<Self> {
+        let log = Arc::new(TransactionLog::open(
+            std::path::Path::new(queue_path).with_extension("txnlog"),
+        )?);
+        let queue = builder
+            .with_deduplication(DEDUPLICATION_WINDOW)
+            .with_persistence(queue_path)
+            .with_transaction_log(Arc::clone(&log))
+            .try_build()?;
+        let path = std::path::Path::new(queue_path);
+        let directory = Directory::open(path.with_extension("directory"))?;
+        let name = path.file_stem().unwrap_or_default().to_string_lossy();
+        // the directory doesn't send in a transaction, so a transactional queue stays out
+        if queue.check_transactional(false).is_ok() {
+            directory.register(&name, Arc::new(queue.clone()))?;
+        }
+        let outgoing = Arc::new(OutgoingQueues::open(path.with_extension("outgoing"))?);
+        outgoing.register_saved(&directory)?;
+        let router = Router::load(directory.clone(), path.with_extension("routes"))?;
         Ok(QueueServer {
             queue: Arc::new(Mutex::new(queue)),
+            log,
+            branches: Arc::new(Branches::new(BRANCH_TIMEOUT)),
+            directory,
+            router,
+            outgoing,
         })
     }
 
@@ -53,8 +290,15 @@ impl QueueServer {
         for stream in listener.incoming() {
             let stream = stream?;
             let queue = Arc::clone(&self.queue);
+            let log = Arc::clone(&self.log);
+            let branches = Arc::clone(&self.branches);
+            let directory = self.directory.clone();
+            let router = self.router.clone();
+            let outgoing = Arc::clone(&self.outgoing);
             thread::spawn(move || {
-                if let Err(e) = handle_client(stream, queue) {
+                let result =
+                    handle_client(stream, queue, log, branches, directory, router, outgoing);
+                if let Err(e) = result {
                     eprintln!("Error handling client: {}", e);
                 }
             });
@@ -63,15 +307,25 @@ impl QueueServer {
     }
 }
 
-fn handle_client(mut stream: TcpStream, queue: Arc<Mutex<Queue>>) -> Result<()> {
-    let mut buffer = [0; 1024];
-    loop {
-        let bytes_read = stream.read(&mut buffer)?;
-        if bytes_read == 0 {
-            return Ok(());
-        }
-
-        let received_message: ReceivedMessage = serde_json::from_slice(&buffer[..bytes_read])?;
+fn handle_client<T>(
+    mut stream: TcpStream,
+    queue: Arc<Mutex<Queue<EmptyJournal, T>>>,
+    log: Arc<TransactionLog>,
+    branches: Arc<Branches>,
+    directory: Directory,
+    router: Router,
+    outgoing: Arc<OutgoingQueues>,
+) -> Result<()>
+where
+    T: TransactionalFeature + Clone + 'static,
+{
+    let reader = stream.try_clone()?;
+    let requests = serde_json::Deserializer::from_reader(reader).into_iter::<ReceivedMessage>();
+    // transactions begun on this connection; any left open when it closes are aborted as
+    // they are dropped
+    let mut transactions = HashMap::new();
+    for received_message in requests {
+        let received_message = received_message?;
         let response = match received_message {
             ReceivedMessage::Enqueue { content } => {
                 let mut queue = queue.lock().unwrap();
@@ -93,11 +347,381 @@ fn handle_client(mut stream: TcpStream, queue: Arc<Mutex<Queue>>) -> Result<()>
                     },
                 }
             }
+            ReceivedMessage::EnqueueMessage { message } => {
+                let mut queue = queue.lock().unwrap();
+                let id = message.dedup_key().to_string();
+                match queue.send_checked(message) {
+                    Ok(SendOutcome::Enqueued) => Response::Success,
+                    Ok(SendOutcome::Duplicate) => Response::Duplicate { id },
+                    Err(e @ MSMQError::TransactionRequired(_)) => Response::Rejected {
+                        message: e.to_string(),
+                    },
+                    Err(e) => Response::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            ReceivedMessage::EnqueueBatch { contents } => {
+                let messages = contents
+                    .iter()
+                    .map(|content| Message::new(content))
+                    .collect();
+                let mut queue = queue.lock().unwrap();
+                match queue.send_batch(messages) {
+                    Ok(_) => Response::Success,
+                    Err(e) => Response::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            ReceivedMessage::BeginTransaction => {
+                let transaction = DistributedTransaction::new().with_log(Arc::clone(&log));
+                let transaction_id = transaction.id().to_string();
+                transactions.insert(transaction_id.clone(), Arc::new(transaction));
+                Response::TransactionStarted { transaction_id }
+            }
+            ReceivedMessage::Commit { transaction_id } => {
+                match transactions
+                    .remove(&transaction_id)
+                    .ok_or(MSMQError::TransactionNotFound)
+                    .and_then(|transaction| transaction.commit())
+                {
+                    Ok(_) => Response::Success,
+                    Err(e) => Response::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            ReceivedMessage::Abort { transaction_id } => {
+                match transactions
+                    .remove(&transaction_id)
+                    .ok_or(MSMQError::TransactionNotFound)
+                    .and_then(|transaction| transaction.abort())
+                {
+                    Ok(_) => Response::Success,
+                    Err(e) => Response::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            ReceivedMessage::EnqueueInTransaction {
+                transaction_id,
+                content,
+            } => {
+                match find_transaction(&transactions, &branches, &transaction_id).and_then(
+                    |transaction| {
+                        let mut queue = queue.lock().unwrap();
+                        queue.send_distributed_transactional(Message::new(&content), &transaction)
+                    },
+                ) {
+                    Ok(_) => Response::Success,
+                    Err(e) => Response::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            ReceivedMessage::DequeueInTransaction { transaction_id } => {
+                match find_transaction(&transactions, &branches, &transaction_id).and_then(
+                    |transaction| {
+                        let queue = queue.lock().unwrap();
+                        queue.receive_distributed_transactional(&transaction)
+                    },
+                ) {
+                    Ok(Some(msg)) => Response::Dequeued {
+                        content: msg.content().to_string(),
+                    },
+                    Ok(None) => Response::Error {
+                        message: "Queue is empty".to_string(),
+                    },
+                    Err(e) => Response::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            ReceivedMessage::JoinTransaction { transaction_id } => {
+                let mut open = branches.open.lock().unwrap();
+                // branches whose coordinator went away were aborted by their timeout
+                open.retain(|_, branch| branch.state() != TransactionState::Aborted);
+                open.entry(transaction_id.clone()).or_insert_with(|| {
+                    Arc::new(
+                        DistributedTransaction::branch(&transaction_id)
+                            .with_log(Arc::clone(&log))
+                            .with_timeout(branches.timeout),
+                    )
+                });
+                Response::Success
+            }
+            ReceivedMessage::Prepare { transaction_id } => {
+                let branch = branches.open.lock().unwrap().get(&transaction_id).cloned();
+                match branch
+                    .ok_or(MSMQError::TransactionNotFound)
+                    .and_then(|branch| branch.prepare())
+                {
+                    Ok(_) => Response::Success,
+                    Err(e) => Response::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            ReceivedMessage::CommitPrepared { transaction_id } => {
+                match finish_branch(&queue, &log, &branches, &transaction_id, true) {
+                    Ok(_) => Response::Success,
+                    Err(e) => Response::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            ReceivedMessage::AbortPrepared { transaction_id } => {
+                match finish_branch(&queue, &log, &branches, &transaction_id, false) {
+                    Ok(_) => Response::Success,
+                    Err(e) => Response::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            ReceivedMessage::EnqueueTransactional { message } => {
+                let mut queue = queue.lock().unwrap();
+                let dtx = DistributedTransaction::new();
+                match queue
+                    .send_distributed_transactional(message, &dtx)
+                    .and_then(|_| dtx.commit())
+                {
+                    Ok(_) => Response::Success,
+                    Err(e) => Response::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            ReceivedMessage::DequeueBatch { max, timeout_ms } => {
+                // wait on a handle to the queue so other clients can keep enqueueing meanwhile
+                let mut queue = queue.lock().unwrap().clone();
+                let messages = queue.receive_batch(max, Duration::from_millis(timeout_ms));
+                Response::DequeuedBatch {
+                    contents: messages
+                        .iter()
+                        .map(|msg| msg.content().to_string())
+                        .collect(),
+                }
+            }
+            ReceivedMessage::MoveToSubqueue { id, format_name } => {
+                let mut queue = queue.lock().unwrap();
+                match served_subqueue(&queue, &format_name)
+                    .and_then(|subqueue| queue.move_to_subqueue(&id, subqueue))
+                {
+                    Ok(_) => Response::Success,
+                    Err(e) => Response::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            ReceivedMessage::DequeueSubqueue { format_name } => {
+                let mut queue = queue.lock().unwrap();
+                match served_subqueue(&queue, &format_name) {
+                    Ok(subqueue) => match queue.receive_from_subqueue(subqueue) {
+                        Some(msg) => Response::Dequeued {
+                            content: msg.content().to_string(),
+                        },
+                        None => Response::Error {
+                            message: "Queue is empty".to_string(),
+                        },
+                    },
+                    Err(e) => Response::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            ReceivedMessage::PeekSubqueue { format_name } => {
+                let queue = queue.lock().unwrap();
+                match served_subqueue(&queue, &format_name) {
+                    Ok(subqueue) => match queue.peek_subqueue(subqueue) {
+                        Some(msg) => Response::Peeked {
+                            content: msg.content().to_string(),
+                        },
+                        None => Response::Error {
+                            message: "Queue is empty".to_string(),
+                        },
+                    },
+                    Err(e) => Response::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            ReceivedMessage::DequeueWhere { selector } => match Selector::parse(&selector) {
+                Ok(selector) => {
+                    let mut queue = queue.lock().unwrap();
+                    match queue.receive_selected(&selector) {
+                        Some(msg) => Response::Dequeued {
+                            content: msg.content().to_string(),
+                        },
+                        None => Response::Error {
+                            message: "No matching message".to_string(),
+                        },
+                    }
+                }
+                Err(e) => Response::Error {
+                    message: e.to_string(),
+                },
+            },
+            ReceivedMessage::SendTo {
+                destination,
+                message,
+            } => match directory.send(&destination, message.cast()) {
+                Ok(_) => Response::Success,
+                Err(e) => Response::Error {
+                    message: e.to_string(),
+                },
+            },
+            ReceivedMessage::SetAlias { alias, target } => {
+                match directory.set_alias(&alias, &target) {
+                    Ok(_) => Response::Success,
+                    Err(e) => Response::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            ReceivedMessage::DefineDistributionList { name, members } => {
+                let members: Vec<_> = members.iter().map(String::as_str).collect();
+                match directory.define_list(&name, &members) {
+                    Ok(_) => Response::Success,
+                    Err(e) => Response::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            ReceivedMessage::RemoveDestination { name } => match directory.remove(&name) {
+                Ok(_) => Response::Success,
+                Err(e) => Response::Error {
+                    message: e.to_string(),
+                },
+            },
+            ReceivedMessage::RegisterRemoteDestination { name, address } => {
+                match outgoing.register(&name, &address, &directory) {
+                    Ok(_) => Response::Success,
+                    Err(e) => Response::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            ReceivedMessage::Route { message } => match router.send(message.cast()) {
+                Ok(destination) => Response::Routed { destination },
+                Err(e) => Response::Error {
+                    message: e.to_string(),
+                },
+            },
+            ReceivedMessage::ReloadRoutes => match router.reload() {
+                Ok(_) => Response::Success,
+                Err(e) => Response::Error {
+                    message: e.to_string(),
+                },
+            },
+            ReceivedMessage::SendRemote { address, message } => {
+                match outgoing.send(&address, message.cast()) {
+                    Ok(_) => Response::Success,
+                    Err(e) => Response::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
+            ReceivedMessage::ListOutgoing => Response::OutgoingQueues {
+                queues: outgoing.status(),
+            },
+            ReceivedMessage::PauseOutgoing { address } => match outgoing.pause(&address) {
+                Ok(_) => Response::Success,
+                Err(e) => Response::Error {
+                    message: e.to_string(),
+                },
+            },
+            ReceivedMessage::ResumeOutgoing { address } => match outgoing.resume(&address) {
+                Ok(_) => Response::Success,
+                Err(e) => Response::Error {
+                    message: e.to_string(),
+                },
+            },
+            ReceivedMessage::ListInDoubtTransactions => Response::InDoubtTransactions {
+                transactions: log.in_doubt(),
+            },
+            ReceivedMessage::ResolveTransaction { id, commit } => {
+                let queue = queue.lock().unwrap().clone();
+                let name = queue.name.clone();
+                let resources: [(&str, Arc<dyn ResourceManager>); 1] = [(&name, Arc::new(queue))];
+                match DistributedTransaction::resolve(&log, &id, commit, &resources) {
+                    Ok(_) => Response::Success,
+                    Err(e) => Response::Error {
+                        message: e.to_string(),
+                    },
+                }
+            }
         };
 
         let response_json = serde_json::to_vec(&response)?;
         stream.write_all(&response_json)?;
     }
+    Ok(())
+}
+
+/// Looks up a transaction begun on this connection or joined on behalf of a coordinator.
+fn find_transaction(
+    transactions: &HashMap<String, Arc<DistributedTransaction>>,
+    branches: &Branches,
+    transaction_id: &str,
+) -> Result<Arc<DistributedTransaction>> {
+    transactions
+        .get(transaction_id)
+        .cloned()
+        .or_else(|| branches.open.lock().unwrap().get(transaction_id).cloned())
+        .ok_or(MSMQError::TransactionNotFound)
+}
+
+/// Applies a coordinator's decision to the local branch of `transaction_id`. A branch that
+/// no longer exists because the server restarted is resolved from the log, and one that was
+/// already finished is left alone, so coordinators can safely repeat a decision.
+fn finish_branch<T>(
+    queue: &Mutex<Queue<EmptyJournal, T>>,
+    log: &TransactionLog,
+    branches: &Branches,
+    transaction_id: &str,
+    commit: bool,
+) -> Result<()>
+where
+    T: TransactionalFeature + Clone + 'static,
+{
+    let branch = branches.open.lock().unwrap().remove(transaction_id);
+    match branch {
+        Some(branch) if finished(branch.state(), commit) => Ok(()),
+        Some(branch) if commit => branch.commit(),
+        Some(branch) => branch.abort(),
+        None if log.is_in_doubt(transaction_id) => {
+            let queue = queue.lock().unwrap().clone();
+            let name = queue.name.clone();
+            let resources: [(&str, Arc<dyn ResourceManager>); 1] = [(&name, Arc::new(queue))];
+            DistributedTransaction::resolve(log, transaction_id, commit, &resources)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Subqueue named by `format_name`, like `orders;poison`, whose queue part has to be the
+/// served queue, known by its file stem.
+fn served_subqueue<'a, T>(queue: &Queue<EmptyJournal, T>, format_name: &'a str) -> Result<&'a str> {
+    let (queue_name, subqueue) = subqueue::parse_format_name(format_name);
+    let subqueue = subqueue.ok_or_else(|| MSMQError::InvalidFormatName(format_name.to_string()))?;
+    let served = std::path::Path::new(&queue.name)
+        .file_stem()
+        .unwrap_or_default()
+        .to_string_lossy();
+    if queue_name != served {
+        return Err(MSMQError::QueueNotFound(queue_name.to_string()));
+    }
+    Ok(subqueue)
+}
+
+/// Whether a branch in `state` already has the outcome a coordinator asks for.
+fn finished(state: TransactionState, commit: bool) -> bool {
+    match state {
+        TransactionState::Committed => commit,
+        TransactionState::Aborted => !commit,
+        _ => false,
+    }
 }
 
 pub fn run_server(queue_path: &str, address: &str) -> Result<()> {
@@ -105,6 +729,12 @@ pub fn run_server(queue_path: &str, address: &str) -> Result<()> {
     server.start(address)
 }
 
+/// Like [`run_server`], but serves a transactional queue.
+pub fn run_transactional_server(queue_path: &str, address: &str) -> Result<()> {
+    let server = QueueServer::transactional(queue_path)?;
+    server.start(address)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -112,6 +742,11 @@ mod tests {
     use std::net::TcpStream;
     use std::thread;
     use std::time::Duration;
+    use tempfile::TempDir;
+
+    fn test_queue_path(dir: &TempDir, file_name: &str) -> String {
+        dir.path().join(file_name).to_string_lossy().to_string()
+    }
 
     fn start_test_server(queue_path: String, address: String) -> thread::JoinHandle<()> {
         thread::spawn(move || {
@@ -120,21 +755,41 @@ mod tests {
         })
     }
 
+    fn start_transactional_test_server(
+        queue_path: String,
+        address: String,
+    ) -> thread::JoinHandle<()> {
+        thread::spawn(move || {
+            let server = QueueServer::transactional(&queue_path).unwrap();
+            server.start(&address).unwrap();
+        })
+    }
+
     fn send_message(address: &str, message: ReceivedMessage) -> Response {
         let mut stream = TcpStream::connect(address).unwrap();
+        request(&mut stream, message)
+    }
+
+    /// Sends `message` over an open connection and reads the response.
+    fn request(stream: &mut TcpStream, message: ReceivedMessage) -> Response {
         let message_json = serde_json::to_vec(&message).unwrap();
         stream.write_all(&message_json).unwrap();
 
-        let mut buffer = [0; 1024];
-        let bytes_read = stream.read(&mut buffer).unwrap();
-        serde_json::from_slice(&buffer[..bytes_read]).unwrap()
+        serde_json::Deserializer::from_reader(&*stream)
+            .into_iter()
+            .next()
+            .unwrap()
+            .unwrap()
     }
 
     #[test]
     fn test_enqueue_and_dequeue() {
         let address = "127.0.0.1:8001".to_string();
-        let server_handle =
-            start_test_server("./test_enqueue_dequeue.msmq".to_string(), address.clone());
+        let dir = tempfile::tempdir().unwrap();
+        let server_handle = start_test_server(
+            test_queue_path(&dir, "test_enqueue_dequeue.msmq"),
+            address.clone(),
+        );
         thread::sleep(Duration::from_millis(100)); // Give the server time to start
 
         let enqueue_response = send_message(
@@ -160,8 +815,11 @@ mod tests {
     #[test]
     fn test_multiple_clients() {
         let address = "127.0.0.1:8002".to_string();
-        let server_handle =
-            start_test_server("./test_multiple_clients.msmq".to_string(), address.clone());
+        let dir = tempfile::tempdir().unwrap();
+        let server_handle = start_test_server(
+            test_queue_path(&dir, "test_multiple_clients.msmq"),
+            address.clone(),
+        );
         thread::sleep(Duration::from_millis(100)); // Give the server time to start
 
         let address_clone = address.clone();
@@ -208,10 +866,160 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_dequeue_with_selector() {
+        let address = "127.0.0.1:8004".to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let server_handle = start_test_server(
+            test_queue_path(&dir, "test_dequeue_selector.msmq"),
+            address.clone(),
+        );
+        thread::sleep(Duration::from_millis(100));
+
+        for (content, label) in [("Invoice", "invoices"), ("Order", "orders")] {
+            let response = send_message(
+                &address,
+                ReceivedMessage::EnqueueMessage {
+                    message: Message::new(content).with_label(label),
+                },
+            );
+            assert!(matches!(response, Response::Success));
+        }
+
+        let dequeue_response = send_message(
+            &address,
+            ReceivedMessage::DequeueWhere {
+                selector: "label = 'orders'".to_string(),
+            },
+        );
+        assert!(matches!(dequeue_response, Response::Dequeued { content } if content == "Order"));
+
+        let no_match_response = send_message(
+            &address,
+            ReceivedMessage::DequeueWhere {
+                selector: "label = 'orders'".to_string(),
+            },
+        );
+        assert!(
+            matches!(no_match_response, Response::Error { message } if message == "No matching message")
+        );
+    }
+
+    #[test]
+    fn test_batch_enqueue_and_dequeue() {
+        let address = "127.0.0.1:8005".to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let server_handle =
+            start_test_server(test_queue_path(&dir, "test_batch.msmq"), address.clone());
+        thread::sleep(Duration::from_millis(100));
+
+        let contents: Vec<String> = (0..1000).map(|i| format!("Message {}", i)).collect();
+        let enqueue_response = send_message(
+            &address,
+            ReceivedMessage::EnqueueBatch {
+                contents: contents.clone(),
+            },
+        );
+        assert!(matches!(enqueue_response, Response::Success));
+
+        let dequeue_response = send_message(
+            &address,
+            ReceivedMessage::DequeueBatch {
+                max: 2000,
+                timeout_ms: 100,
+            },
+        );
+        assert!(
+            matches!(dequeue_response, Response::DequeuedBatch { contents: received } if received == contents)
+        );
+    }
+
+    #[test]
+    fn test_duplicate_enqueue_is_reported() {
+        let address = "127.0.0.1:8006".to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let server_handle =
+            start_test_server(test_queue_path(&dir, "test_dedup.msmq"), address.clone());
+        thread::sleep(Duration::from_millis(100));
+
+        let message = Message::new("Retried message");
+        let first_response = send_message(
+            &address,
+            ReceivedMessage::EnqueueMessage {
+                message: message.clone(),
+            },
+        );
+        assert!(matches!(first_response, Response::Success));
+
+        let retry_response = send_message(
+            &address,
+            ReceivedMessage::EnqueueMessage {
+                message: message.clone(),
+            },
+        );
+        assert!(matches!(retry_response, Response::Duplicate { id } if id == message.id()));
+    }
+
+    #[test]
+    fn test_poison_subqueue() {
+        let address = "127.0.0.1:8007".to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let server_handle =
+            start_test_server(test_queue_path(&dir, "test_subqueue.msmq"), address.clone());
+        thread::sleep(Duration::from_millis(100));
+
+        let message = Message::new("Poison message");
+        let id = message.id().to_string();
+        send_message(&address, ReceivedMessage::EnqueueMessage { message });
+
+        let move_response = send_message(
+            &address,
+            ReceivedMessage::MoveToSubqueue {
+                id,
+                format_name: "test_subqueue;poison".to_string(),
+            },
+        );
+        assert!(matches!(move_response, Response::Success));
+
+        let dequeue_response = send_message(&address, ReceivedMessage::Dequeue);
+        assert!(matches!(dequeue_response, Response::Error { .. }));
+
+        let peek_response = send_message(
+            &address,
+            ReceivedMessage::PeekSubqueue {
+                format_name: "test_subqueue;poison".to_string(),
+            },
+        );
+        assert!(
+            matches!(peek_response, Response::Peeked { content } if content == "Poison message")
+        );
+
+        let dequeue_response = send_message(
+            &address,
+            ReceivedMessage::DequeueSubqueue {
+                format_name: "test_subqueue;poison".to_string(),
+            },
+        );
+        assert!(
+            matches!(dequeue_response, Response::Dequeued { content } if content == "Poison message")
+        );
+
+        for format_name in ["poison", "other;poison"] {
+            let response = send_message(
+                &address,
+                ReceivedMessage::PeekSubqueue {
+                    format_name: format_name.to_string(),
+                },
+            );
+            assert!(matches!(response, Response::Error { .. }));
+        }
+    }
+
     #[test]
     fn test_server_persistence() {
         let address = "127.0.0.1:8003".to_string();
-        let queue_path = "./test_persistence.msmq".to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let queue_path = test_queue_path(&dir, "test_persistence.msmq");
 
         {
             let server_handle = start_test_server(queue_path.clone(), address.clone());
@@ -239,4 +1047,466 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_branch_of_vanished_coordinator_is_aborted() {
+        let address = "127.0.0.1:8030".to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let queue_path = test_queue_path(&dir, "orders.msmq");
+        let server_address = address.clone();
+        thread::spawn(move || {
+            let mut server = QueueServer::transactional(&queue_path).unwrap();
+            server.branches = Arc::new(Branches::new(Duration::from_millis(200)));
+            server.start(&server_address).unwrap();
+        });
+        thread::sleep(Duration::from_millis(100));
+
+        send_message(
+            &address,
+            ReceivedMessage::EnqueueTransactional {
+                message: Message::new("Order"),
+            },
+        );
+        let transaction_id = "vanished-txn".to_string();
+        send_message(
+            &address,
+            ReceivedMessage::JoinTransaction {
+                transaction_id: transaction_id.clone(),
+            },
+        );
+        let received = send_message(
+            &address,
+            ReceivedMessage::DequeueInTransaction {
+                transaction_id: transaction_id.clone(),
+            },
+        );
+        assert!(matches!(received, Response::Dequeued { content } if content == "Order"));
+        assert!(matches!(
+            send_message(&address, ReceivedMessage::Dequeue),
+            Response::Error { .. }
+        ));
+
+        // the coordinator never asks the branch to prepare
+        thread::sleep(Duration::from_millis(400));
+        assert!(matches!(
+            send_message(&address, ReceivedMessage::Prepare { transaction_id }),
+            Response::Error { .. }
+        ));
+        assert!(matches!(
+            send_message(&address, ReceivedMessage::Dequeue),
+            Response::Dequeued { content } if content == "Order"
+        ));
+    }
+
+    #[test]
+    fn test_resolve_in_doubt_transaction() {
+        let address = "127.0.0.1:8008".to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let queue_path = test_queue_path(&dir, "test_in_doubt.msmq");
+
+        // leave a branch in doubt, as if its coordinator crashed after it prepared
+        let txn_id = {
+            let log = Arc::new(
+                TransactionLog::open(std::path::Path::new(&queue_path).with_extension("txnlog"))
+                    .unwrap(),
+            );
+            let mut queue = QueueBuilder::new(&queue_path)
+                .with_transactional()
+                .with_persistence(&queue_path)
+                .with_transaction_log(Arc::clone(&log))
+                .build();
+            let dtx = DistributedTransaction::branch("remote-txn").with_log(log);
+            queue
+                .send_distributed_transactional(Message::new("In doubt"), &dtx)
+                .unwrap();
+            dtx.prepare().unwrap();
+            let txn_id = dtx.id().to_string();
+            std::mem::forget(dtx);
+            txn_id
+        };
+
+        let server_handle = start_transactional_test_server(queue_path, address.clone());
+        thread::sleep(Duration::from_millis(100));
+
+        let list_response = send_message(&address, ReceivedMessage::ListInDoubtTransactions);
+        assert!(
+            matches!(list_response, Response::InDoubtTransactions { transactions } if transactions.len() == 1 && transactions[0].id == txn_id)
+        );
+
+        let dequeue_response = send_message(&address, ReceivedMessage::Dequeue);
+        assert!(matches!(dequeue_response, Response::Error { .. }));
+
+        let resolve_response = send_message(
+            &address,
+            ReceivedMessage::ResolveTransaction {
+                id: txn_id.clone(),
+                commit: true,
+            },
+        );
+        assert!(matches!(resolve_response, Response::Success));
+
+        let dequeue_response = send_message(&address, ReceivedMessage::Dequeue);
+        assert!(
+            matches!(dequeue_response, Response::Dequeued { content } if content == "In doubt")
+        );
+
+        let resolve_response = send_message(
+            &address,
+            ReceivedMessage::ResolveTransaction {
+                id: txn_id,
+                commit: false,
+            },
+        );
+        assert!(matches!(resolve_response, Response::Error { .. }));
+    }
+
+    #[test]
+    fn test_transactional_server_rejects_plain_enqueue() {
+        let address = "127.0.0.1:8009".to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let server_handle = start_transactional_test_server(
+            test_queue_path(&dir, "test_transactional.msmq"),
+            address.clone(),
+        );
+        thread::sleep(Duration::from_millis(100));
+
+        let enqueue_response = send_message(
+            &address,
+            ReceivedMessage::Enqueue {
+                content: "Outside a transaction".to_string(),
+            },
+        );
+        assert!(
+            matches!(enqueue_response, Response::Error { message } if message.contains("only accepts sends within a transaction"))
+        );
+
+        let batch_response = send_message(
+            &address,
+            ReceivedMessage::EnqueueBatch {
+                contents: vec!["Outside a transaction".to_string()],
+            },
+        );
+        assert!(matches!(batch_response, Response::Error { .. }));
+    }
+
+    #[test]
+    fn test_sequenced_retries_are_delivered_once_in_order() {
+        let address = "127.0.0.1:8010".to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let server_handle = start_transactional_test_server(
+            test_queue_path(&dir, "test_sequence.msmq"),
+            address.clone(),
+        );
+        thread::sleep(Duration::from_millis(100));
+
+        // the first message is delayed and retried after the second one arrived
+        for number in [2, 1, 1, 2] {
+            let message =
+                Message::new(&format!("Message {}", number)).with_sequence("client", number);
+            let response =
+                send_message(&address, ReceivedMessage::EnqueueTransactional { message });
+            assert!(matches!(response, Response::Success));
+        }
+
+        for expected in ["Message 1", "Message 2"] {
+            let dequeue_response = send_message(&address, ReceivedMessage::Dequeue);
+            assert!(
+                matches!(dequeue_response, Response::Dequeued { content } if content == expected)
+            );
+        }
+        let dequeue_response = send_message(&address, ReceivedMessage::Dequeue);
+        assert!(matches!(dequeue_response, Response::Error { .. }));
+    }
+
+    #[test]
+    fn test_transaction_over_connection() {
+        let address = "127.0.0.1:8011".to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let server_handle = start_transactional_test_server(
+            test_queue_path(&dir, "test_tcp_transaction.msmq"),
+            address.clone(),
+        );
+        thread::sleep(Duration::from_millis(100));
+
+        let mut stream = TcpStream::connect(&address).unwrap();
+        let Response::TransactionStarted { transaction_id } =
+            request(&mut stream, ReceivedMessage::BeginTransaction)
+        else {
+            panic!("transaction wasn't started");
+        };
+        for content in ["First", "Second"] {
+            let response = request(
+                &mut stream,
+                ReceivedMessage::EnqueueInTransaction {
+                    transaction_id: transaction_id.clone(),
+                    content: content.to_string(),
+                },
+            );
+            assert!(matches!(response, Response::Success));
+        }
+
+        let dequeue_response = send_message(&address, ReceivedMessage::Dequeue);
+        assert!(matches!(dequeue_response, Response::Error { .. }));
+
+        let commit_response = request(
+            &mut stream,
+            ReceivedMessage::Commit {
+                transaction_id: transaction_id.clone(),
+            },
+        );
+        assert!(matches!(commit_response, Response::Success));
+        let commit_response = request(&mut stream, ReceivedMessage::Commit { transaction_id });
+        assert!(matches!(commit_response, Response::Error { .. }));
+
+        let dequeue_response = send_message(&address, ReceivedMessage::Dequeue);
+        assert!(matches!(dequeue_response, Response::Dequeued { content } if content == "First"));
+    }
+
+    #[test]
+    fn test_dropped_connection_aborts_open_transactions() {
+        let address = "127.0.0.1:8012".to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let server_handle = start_transactional_test_server(
+            test_queue_path(&dir, "test_tcp_abort.msmq"),
+            address.clone(),
+        );
+        thread::sleep(Duration::from_millis(100));
+
+        let message = Message::new("Committed").with_sequence("client", 1);
+        send_message(&address, ReceivedMessage::EnqueueTransactional { message });
+
+        {
+            let mut stream = TcpStream::connect(&address).unwrap();
+            let Response::TransactionStarted { transaction_id } =
+                request(&mut stream, ReceivedMessage::BeginTransaction)
+            else {
+                panic!("transaction wasn't started");
+            };
+            let dequeue_response = request(
+                &mut stream,
+                ReceivedMessage::DequeueInTransaction {
+                    transaction_id: transaction_id.clone(),
+                },
+            );
+            assert!(
+                matches!(dequeue_response, Response::Dequeued { content } if content == "Committed")
+            );
+            request(
+                &mut stream,
+                ReceivedMessage::EnqueueInTransaction {
+                    transaction_id,
+                    content: "Uncommitted".to_string(),
+                },
+            );
+        }
+        thread::sleep(Duration::from_millis(100));
+
+        let dequeue_response = send_message(&address, ReceivedMessage::Dequeue);
+        assert!(
+            matches!(dequeue_response, Response::Dequeued { content } if content == "Committed")
+        );
+        let dequeue_response = send_message(&address, ReceivedMessage::Dequeue);
+        assert!(matches!(dequeue_response, Response::Error { .. }));
+    }
+
+    #[test]
+    fn test_send_to_alias_and_distribution_list() {
+        let address = "127.0.0.1:8019".to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let _server_handle =
+            start_test_server(test_queue_path(&dir, "orders_v2.msmq"), address.clone());
+        thread::sleep(Duration::from_millis(100));
+
+        let requests = [
+            ReceivedMessage::SetAlias {
+                alias: "orders".to_string(),
+                target: "orders_v2".to_string(),
+            },
+            ReceivedMessage::DefineDistributionList {
+                name: "everyone".to_string(),
+                members: vec!["orders".to_string(), "orders_v2".to_string()],
+            },
+            ReceivedMessage::SendTo {
+                destination: "everyone".to_string(),
+                message: Message::new("Price update"),
+            },
+        ];
+        for message in requests {
+            assert!(matches!(send_message(&address, message), Response::Success));
+        }
+
+        let response = send_message(
+            &address,
+            ReceivedMessage::SendTo {
+                destination: "invoices".to_string(),
+                message: Message::new("Invoice"),
+            },
+        );
+        assert!(matches!(response, Response::Error { .. }));
+
+        // the list reaches the queue once, even though it's listed under two names
+        let dequeue_response = send_message(&address, ReceivedMessage::Dequeue);
+        assert!(
+            matches!(dequeue_response, Response::Dequeued { content } if content == "Price update")
+        );
+        let dequeue_response = send_message(&address, ReceivedMessage::Dequeue);
+        assert!(matches!(dequeue_response, Response::Error { .. }));
+    }
+
+    #[test]
+    fn test_distribution_list_spans_remote_queue() {
+        let address = "127.0.0.1:8025".to_string();
+        let remote_address = "127.0.0.1:8026".to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let _server_handle =
+            start_test_server(test_queue_path(&dir, "orders.msmq"), address.clone());
+        let _remote_handle = start_test_server(
+            test_queue_path(&dir, "billing.msmq"),
+            remote_address.clone(),
+        );
+        thread::sleep(Duration::from_millis(100));
+
+        let requests = [
+            ReceivedMessage::RegisterRemoteDestination {
+                name: "billing".to_string(),
+                address: remote_address.clone(),
+            },
+            ReceivedMessage::DefineDistributionList {
+                name: "everyone".to_string(),
+                members: vec!["orders".to_string(), "billing".to_string()],
+            },
+            ReceivedMessage::SendTo {
+                destination: "everyone".to_string(),
+                message: Message::new("Price update"),
+            },
+        ];
+        for message in requests {
+            assert!(matches!(send_message(&address, message), Response::Success));
+        }
+
+        let dequeue_response = send_message(&address, ReceivedMessage::Dequeue);
+        assert!(
+            matches!(dequeue_response, Response::Dequeued { content } if content == "Price update")
+        );
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        let forwarded = loop {
+            match send_message(&remote_address, ReceivedMessage::Dequeue) {
+                Response::Dequeued { content } => break Some(content),
+                _ if std::time::Instant::now() < deadline => {
+                    thread::sleep(Duration::from_millis(20))
+                }
+                _ => break None,
+            }
+        };
+        assert_eq!(forwarded.as_deref(), Some("Price update"));
+
+        let remove_response = send_message(
+            &address,
+            ReceivedMessage::RemoveDestination {
+                name: "billing".to_string(),
+            },
+        );
+        assert!(matches!(remove_response, Response::Success));
+        let send_response = send_message(
+            &address,
+            ReceivedMessage::SendTo {
+                destination: "everyone".to_string(),
+                message: Message::new("Second update"),
+            },
+        );
+        assert!(matches!(send_response, Response::Error { .. }));
+    }
+
+    #[test]
+    fn test_transactional_queue_is_not_a_directory_destination() {
+        let address = "127.0.0.1:8027".to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let _server_handle =
+            start_transactional_test_server(test_queue_path(&dir, "ledger.msmq"), address.clone());
+        thread::sleep(Duration::from_millis(100));
+
+        let response = send_message(
+            &address,
+            ReceivedMessage::SendTo {
+                destination: "ledger".to_string(),
+                message: Message::new("Entry"),
+            },
+        );
+        assert!(matches!(response, Response::Error { .. }));
+        let dequeue_response = send_message(&address, ReceivedMessage::Dequeue);
+        assert!(matches!(dequeue_response, Response::Error { .. }));
+    }
+
+    #[test]
+    fn test_route_with_reloaded_rules() {
+        let address = "127.0.0.1:8020".to_string();
+        let dir = tempfile::tempdir().unwrap();
+        let queue_path = test_queue_path(&dir, "orders.msmq");
+        let routes_path = dir.path().join("orders.routes");
+        std::fs::write(&routes_path, r#"{ "dead_letter": "orders" }"#).unwrap();
+        let _server_handle = start_test_server(queue_path, address.clone());
+        thread::sleep(Duration::from_millis(100));
+
+        let route = |label: &str| {
+            send_message(
+                &address,
+                ReceivedMessage::Route {
+                    message: Message::new(label).with_label(label),
+                },
+            )
+        };
+        assert!(
+            matches!(route("order"), Response::Routed { destination } if destination == "orders")
+        );
+
+        std::fs::write(
+            &routes_path,
+            r#"{ "rules": [{ "when": "label = 'order'", "to": "orders" }] }"#,
+        )
+        .unwrap();
+        let reload_response = send_message(&address, ReceivedMessage::ReloadRoutes);
+        assert!(matches!(reload_response, Response::Success));
+        assert!(matches!(route("order"), Response::Routed { .. }));
+        assert!(matches!(route("note"), Response::Error { .. }));
+    }
+
+    #[test]
+    fn test_send_remote_forwards_to_other_server() {
+        let (local, remote) = ("127.0.0.1:8023".to_string(), "127.0.0.1:8024".to_string());
+        let dir = tempfile::tempdir().unwrap();
+        let _local_handle = start_test_server(test_queue_path(&dir, "local.msmq"), local.clone());
+        let _remote_handle =
+            start_test_server(test_queue_path(&dir, "remote.msmq"), remote.clone());
+        thread::sleep(Duration::from_millis(100));
+
+        let response = send_message(
+            &local,
+            ReceivedMessage::SendRemote {
+                address: remote.clone(),
+                message: Message::new("Forwarded"),
+            },
+        );
+        assert!(matches!(response, Response::Success));
+
+        let mut dequeue_response = send_message(&remote, ReceivedMessage::Dequeue);
+        for _ in 0..50 {
+            if matches!(dequeue_response, Response::Dequeued { .. }) {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+            dequeue_response = send_message(&remote, ReceivedMessage::Dequeue);
+        }
+        assert!(
+            matches!(dequeue_response, Response::Dequeued { content } if content == "Forwarded")
+        );
+
+        let Response::OutgoingQueues { queues } =
+            send_message(&local, ReceivedMessage::ListOutgoing)
+        else {
+            panic!("expected the outgoing queues");
+        };
+        assert_eq!(queues.len(), 1);
+        assert_eq!(queues[0].address, remote);
+        assert_eq!(queues[0].pending, 0);
+    }
 }
@@ -1,38 +1,282 @@
 use crate::features::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Default, Clone)]
+#[derive(Default, Serialize, Deserialize)]
+#[serde(bound = "")]
 pub struct Message<E: ?Sized = dyn EncryptFeature> {
+    #[serde(default = "generate_id")]
+    id: String,
     content: String,
+    #[serde(default)]
+    label: String,
+    #[serde(default)]
+    priority: u8,
+    #[serde(default)]
+    correlation_id: Option<String>,
+    #[serde(default)]
+    properties: HashMap<String, String>,
+    #[serde(default)]
+    delivery_count: u32,
+    #[serde(default)]
+    abort_count: u32,
+    /// Milliseconds since the unix epoch before which the message can't be received.
+    #[serde(default)]
+    not_before: Option<u64>,
+    #[serde(default)]
+    dedup_key: Option<String>,
+    #[serde(default)]
+    group_id: Option<String>,
+    #[serde(default)]
+    sender_id: Option<String>,
+    /// Position of the message among those committed by `sender_id`, starting at 1.
+    #[serde(default)]
+    sequence_number: Option<u64>,
+    #[serde(skip)]
     state: std::marker::PhantomData<E>,
 }
 
 impl Message<BasicEncryption> {
     pub fn decrypt(self) -> Message<AnonymousEncryption> {
-        Message {
-            content: self.content,
-            state: std::marker::PhantomData,
-        }
+        self.cast()
     }
 }
 
 impl Message<AnonymousEncryption> {
     pub fn encrypt(self) -> Message<BasicEncryption> {
-        Message {
-            content: self.content,
-            state: std::marker::PhantomData,
-        }
+        self.cast()
     }
 }
 
 impl<E: ?Sized> Message<E> {
     pub fn new(content: &str) -> Self {
         Self {
+            id: generate_id(),
             content: content.to_string(),
+            label: String::new(),
+            priority: 0,
+            correlation_id: None,
+            properties: HashMap::new(),
+            delivery_count: 0,
+            abort_count: 0,
+            not_before: None,
+            dedup_key: None,
+            group_id: None,
+            sender_id: None,
+            sequence_number: None,
             state: std::marker::PhantomData,
         }
     }
 
+    pub fn with_label(mut self, label: &str) -> Self {
+        self.label = label.to_string();
+        self
+    }
+
+    pub fn with_priority(mut self, priority: u8) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_correlation_id(mut self, correlation_id: &str) -> Self {
+        self.correlation_id = Some(correlation_id.to_string());
+        self
+    }
+
+    pub fn with_property(mut self, key: &str, value: &str) -> Self {
+        self.properties.insert(key.to_string(), value.to_string());
+        self
+    }
+
+    /// Keeps the message invisible to receivers until `at`.
+    pub fn with_not_before(mut self, at: SystemTime) -> Self {
+        self.not_before = Some(
+            at.duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or_default(),
+        );
+        self
+    }
+
+    /// Keeps the message invisible to receivers for `delay` from now.
+    pub fn with_delay(self, delay: Duration) -> Self {
+        self.with_not_before(SystemTime::now() + delay)
+    }
+
+    /// Key used by queues with a deduplication window instead of the message id.
+    pub fn with_dedup_key(mut self, key: &str) -> Self {
+        self.dedup_key = Some(key.to_string());
+        self
+    }
+
+    /// Puts the message in a group (session) whose messages are consumed in order by one
+    /// consumer at a time.
+    pub fn with_group_id(mut self, group_id: &str) -> Self {
+        self.group_id = Some(group_id.to_string());
+        self
+    }
+
+    /// Names the sender of the message. Transactional queues number each sender's messages
+    /// as they commit and deliver them once and in that order.
+    pub fn with_sender(mut self, sender: &str) -> Self {
+        self.sender_id = Some(sender.to_string());
+        self
+    }
+
+    /// Numbers the message as the `number`th one committed by `sender`, counting from 1,
+    /// as it travels between queues. A message retried over a link keeps its number, so
+    /// the receiving queue can drop the duplicate.
+    pub(crate) fn with_sequence(mut self, sender: &str, number: u64) -> Self {
+        self.sender_id = Some(sender.to_string());
+        self.sequence_number = Some(number);
+        self
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
     pub fn content(&self) -> &String {
         &self.content
     }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.correlation_id.as_deref()
+    }
+
+    pub fn property(&self, key: &str) -> Option<&str> {
+        self.properties.get(key).map(String::as_str)
+    }
+
+    pub fn properties(&self) -> &HashMap<String, String> {
+        &self.properties
+    }
+
+    /// Number of times delivery of this message has failed (nacked or lease expired).
+    pub fn delivery_count(&self) -> u32 {
+        self.delivery_count
+    }
+
+    pub fn not_before(&self) -> Option<SystemTime> {
+        self.not_before
+            .map(|millis| UNIX_EPOCH + Duration::from_millis(millis))
+    }
+
+    /// Whether the message may be handed to a receiver at `now`.
+    pub fn is_due(&self, now: SystemTime) -> bool {
+        self.not_before().is_none_or(|not_before| not_before <= now)
+    }
+
+    pub fn group_id(&self) -> Option<&str> {
+        self.group_id.as_deref()
+    }
+
+    pub fn sender(&self) -> Option<&str> {
+        self.sender_id.as_deref()
+    }
+
+    /// The sender and the number its message was given once committed.
+    pub fn sequence(&self) -> Option<(&str, u64)> {
+        self.sender_id.as_deref().zip(self.sequence_number)
+    }
+
+    /// The explicit dedup key if there is one, the message id otherwise.
+    pub fn dedup_key(&self) -> &str {
+        self.dedup_key.as_deref().unwrap_or(&self.id)
+    }
+
+    /// Number of times a transaction that received this message was aborted.
+    pub fn abort_count(&self) -> u32 {
+        self.abort_count
+    }
+
+    pub(crate) fn record_failed_delivery(&mut self) {
+        self.delivery_count += 1;
+    }
+
+    pub(crate) fn record_abort(&mut self) {
+        self.abort_count += 1;
+    }
+
+    /// Reinterprets the message under another encryption marker, keeping all of its properties.
+    pub(crate) fn cast<F: ?Sized>(self) -> Message<F> {
+        Message {
+            id: self.id,
+            content: self.content,
+            label: self.label,
+            priority: self.priority,
+            correlation_id: self.correlation_id,
+            properties: self.properties,
+            delivery_count: self.delivery_count,
+            abort_count: self.abort_count,
+            not_before: self.not_before,
+            dedup_key: self.dedup_key,
+            group_id: self.group_id,
+            sender_id: self.sender_id,
+            sequence_number: self.sequence_number,
+            state: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<E: ?Sized> Clone for Message<E> {
+    fn clone(&self) -> Self {
+        Self {
+            id: self.id.clone(),
+            content: self.content.clone(),
+            label: self.label.clone(),
+            priority: self.priority,
+            correlation_id: self.correlation_id.clone(),
+            properties: self.properties.clone(),
+            delivery_count: self.delivery_count,
+            abort_count: self.abort_count,
+            not_before: self.not_before,
+            dedup_key: self.dedup_key.clone(),
+            group_id: self.group_id.clone(),
+            sender_id: self.sender_id.clone(),
+            sequence_number: self.sequence_number,
+            state: std::marker::PhantomData,
+        }
+    }
+}
+
+impl<E: ?Sized> fmt::Debug for Message<E> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Message")
+            .field("id", &self.id)
+            .field("content", &self.content)
+            .field("label", &self.label)
+            .field("priority", &self.priority)
+            .field("correlation_id", &self.correlation_id)
+            .field("properties", &self.properties)
+            .field("delivery_count", &self.delivery_count)
+            .field("abort_count", &self.abort_count)
+            .field("not_before", &self.not_before)
+            .field("dedup_key", &self.dedup_key)
+            .field("group_id", &self.group_id)
+            .field("sender_id", &self.sender_id)
+            .field("sequence_number", &self.sequence_number)
+            .finish()
+    }
+}
+
+/// Generates an id that is unique across restarts, used for messages and transactions.
+pub(crate) fn generate_id() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos())
+        .unwrap_or_default();
+    format!("{:x}\\{}", nanos, COUNTER.fetch_add(1, Ordering::Relaxed))
 }
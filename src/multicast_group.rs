@@ -1,18 +1,185 @@
+use crate::error::MSMQError;
 use crate::message::Message;
+use crate::multicast_transport::MulticastTransport;
 use crate::Result;
+use std::collections::HashMap;
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
 
+/// A queue that can be added to a [`MulticastGroup`].
+pub(crate) trait GroupMember: Send + Sync {
+    /// Delivers a copy of a message sent to the group.
+    fn deliver(&self, message: Message) -> Result<()>;
+    /// Tells members registered under the same name apart.
+    fn member_id(&self) -> usize;
+}
+
+/// Member queues, keyed by queue name.
+pub(crate) type Members = Mutex<HashMap<String, Arc<dyn GroupMember>>>;
+
+/// Delivers every message sent to it to each of its member queues.
+///
+/// A group bound to a multicast address also reaches the groups bound to that address in
+/// other processes, and delivers what they send to its own members.
+#[derive(Clone)]
 pub struct MulticastGroup {
     name: String,
+    members: Arc<Members>,
+    transport: Option<Arc<MulticastTransport>>,
 }
 
 impl MulticastGroup {
     pub fn new(name: &str) -> Self {
         Self {
             name: name.to_string(),
+            members: Arc::new(Mutex::new(HashMap::new())),
+            transport: None,
         }
     }
 
+    /// Creates a group bound to the multicast `address`, joined on the local `interface`
+    /// (e.g. `Ipv4Addr::LOCALHOST` to stay on this host). Messages lost on the way are
+    /// asked for again from their sender.
+    pub fn bind(name: &str, address: SocketAddrV4, interface: Ipv4Addr) -> Result<Self> {
+        let members = Arc::new(Mutex::new(HashMap::new()));
+        let transport = MulticastTransport::open(name, address, interface, &members)?;
+        Ok(Self {
+            name: name.to_string(),
+            members,
+            transport: Some(transport),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Names of the member queues, in alphabetical order.
+    pub fn members(&self) -> Vec<String> {
+        let mut members: Vec<_> = self
+            .members
+            .lock()
+            .expect("Failed to lock group members")
+            .keys()
+            .cloned()
+            .collect();
+        members.sort();
+        members
+    }
+
+    /// Sends a copy of `message` to every member, and to the groups in other processes if
+    /// the group is bound.
     pub fn send(&self, message: Message) -> Result<()> {
+        if let Some(transport) = &self.transport {
+            transport.publish(message.clone().cast())?;
+        }
+        fan_out(&self.name, &self.members, message)
+    }
+
+    pub(crate) fn join(&self, name: &str, member: Arc<dyn GroupMember>) -> Result<()> {
+        let mut members = self
+            .members
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?;
+        let joined = members
+            .entry(name.to_string())
+            .or_insert_with(|| Arc::clone(&member));
+        if joined.member_id() != member.member_id() {
+            return Err(MSMQError::NameInUse(name.to_string()));
+        }
         Ok(())
     }
+
+    pub(crate) fn leave(&self, name: &str) -> Result<()> {
+        self.members
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?
+            .remove(name);
+        Ok(())
+    }
+}
+
+/// Delivers a copy of `message` to each of `members`, returning the last failure.
+pub(crate) fn fan_out(group: &str, members: &Members, message: Message) -> Result<()> {
+    let members = members
+        .lock()
+        .map_err(|e| MSMQError::Custom(e.to_string()))?
+        .values()
+        .cloned()
+        .collect();
+    deliver_all(group, members, message)
+}
+
+/// Delivers a copy of `message` to each of `members`, sent to the group or topic
+/// `destination`. A member failing doesn't stop delivery to the others; the last failure is
+/// returned.
+pub(crate) fn deliver_all(
+    destination: &str,
+    members: Vec<Arc<dyn GroupMember>>,
+    message: Message,
+) -> Result<()> {
+    let mut result = Ok(());
+    for member in members {
+        if let Err(e) = member.deliver(message.clone()) {
+            tracing::warn!("Failed to deliver to a member of {}: {}", destination, e);
+            result = Err(e);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::QueueOps;
+    use crate::queue_builder::QueueBuilder;
+
+    #[test]
+    fn test_send_reaches_every_member() {
+        let group = MulticastGroup::new("prices");
+        let mut shop = QueueBuilder::new("shop").build();
+        let mut warehouse = QueueBuilder::new("warehouse").build();
+        shop.join_group(&group).unwrap();
+        warehouse.join_group(&group).unwrap();
+        assert_eq!(group.members(), ["shop", "warehouse"]);
+
+        group.send(Message::new("Price update")).unwrap();
+        assert_eq!(shop.receive().unwrap().content(), "Price update");
+        assert_eq!(warehouse.receive().unwrap().content(), "Price update");
+
+        warehouse.leave_group(&group).unwrap();
+        assert_eq!(group.members(), ["shop"]);
+        group.send(Message::new("Second update")).unwrap();
+        assert_eq!(shop.message_count().unwrap(), 1);
+        assert_eq!(warehouse.message_count().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_other_queue_with_a_joined_name_is_rejected() {
+        let group = MulticastGroup::new("prices");
+        let mut shop = QueueBuilder::new("shop").build();
+        let mut other_shop = QueueBuilder::new("shop").build();
+        shop.join_group(&group).unwrap();
+        shop.clone().join_group(&group).unwrap();
+
+        assert!(matches!(
+            other_shop.join_group(&group),
+            Err(MSMQError::NameInUse(name)) if name == "shop"
+        ));
+        group.send(Message::new("Price update")).unwrap();
+        assert_eq!(shop.message_count().unwrap(), 1);
+        assert_eq!(other_shop.message_count().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_transactional_queue_cant_join() {
+        let group = MulticastGroup::new("prices");
+        let mut orders = QueueBuilder::new("orders").with_transactional().build();
+
+        assert!(matches!(
+            orders.join_group(&group),
+            Err(MSMQError::TransactionRequired(_))
+        ));
+        assert!(group.members().is_empty());
+    }
 }
@@ -0,0 +1,460 @@
+use crate::features::AnonymousEncryption;
+use crate::message::{generate_id, Message};
+use crate::multicast_group::{fan_out, Members};
+use crate::sequence::{self, SenderSequence, Sequences};
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Number of sent packets kept for retransmission.
+const HISTORY_LEN: usize = 1024;
+/// How long the transport's threads wait for a packet before checking whether the group
+/// is gone, and how long a receiver waits before asking for missing messages again.
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// How often a sender announces the last message it sent, so receivers notice when the
+/// latest ones were lost.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(500);
+/// How long a receiver asks for missing messages before it gives up on them and delivers
+/// the ones held behind, and how long it remembers a sender that went quiet.
+const HOLD_TIMEOUT: Duration = Duration::from_secs(3);
+
+#[derive(Serialize, Deserialize)]
+enum Packet {
+    Data {
+        sender: String,
+        sequence: u64,
+        message: Box<Message<AnonymousEncryption>>,
+    },
+    /// Asks `sender` to send message `sequence` again.
+    Nack { sender: String, sequence: u64 },
+    /// Announces that `sender` has sent every message up to `last`.
+    Heartbeat { sender: String, last: u64 },
+}
+
+/// Carries a [`MulticastGroup`](crate::multicast_group::MulticastGroup)'s messages over UDP
+/// multicast, in the spirit of PGM: every sender numbers its messages, and receivers hold
+/// the ones after a gap and send the sender a NACK for each missing one. The sender
+/// multicasts the missing message again and the others drop it as a duplicate.
+///
+/// A receiver notices a loss once a later message from the same sender arrives, or, for the
+/// sender's latest messages, once the sender's periodic heartbeat announces them. Messages
+/// still missing after [`HOLD_TIMEOUT`], e.g. because the sender no longer keeps them or
+/// died, are given up on.
+pub(crate) struct MulticastTransport {
+    address: SocketAddrV4,
+    /// Identifies this transport's messages to receivers.
+    sender_id: String,
+    /// Sends messages to the group and receives NACKs for them.
+    socket: UdpSocket,
+    next_sequence: Mutex<u64>,
+    history: Mutex<BTreeMap<u64, Vec<u8>>>,
+}
+
+impl MulticastTransport {
+    /// Joins `address` on `interface`, delivering what other senders multicast there to
+    /// `members` until they are dropped.
+    pub(crate) fn open(
+        group: &str,
+        address: SocketAddrV4,
+        interface: Ipv4Addr,
+        members: &Arc<Members>,
+    ) -> Result<Arc<Self>> {
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_multicast_if_v4(&interface)?;
+        socket.set_multicast_loop_v4(true)?;
+        socket.bind(&SocketAddrV4::new(interface, 0).into())?;
+        let socket = UdpSocket::from(socket);
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+        let transport = Arc::new(Self {
+            address,
+            sender_id: generate_id(),
+            socket,
+            next_sequence: Mutex::new(1),
+            history: Mutex::new(BTreeMap::new()),
+        });
+
+        // Other processes on the host may have joined the group on the same port.
+        let receiver = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        receiver.set_reuse_address(true)?;
+        // BSDs, macOS among them, only share a port between sockets that all reuse it
+        #[cfg(any(
+            target_os = "macos",
+            target_os = "ios",
+            target_os = "freebsd",
+            target_os = "openbsd",
+            target_os = "netbsd",
+            target_os = "dragonfly"
+        ))]
+        receiver.set_reuse_port(true)?;
+        receiver.bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, address.port()).into())?;
+        let receiver = UdpSocket::from(receiver);
+        receiver.join_multicast_v4(address.ip(), &interface)?;
+        receiver.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        let weak = Arc::downgrade(&transport);
+        thread::spawn(move || serve_senders(weak));
+        let group = group.to_string();
+        let sender_id = transport.sender_id.clone();
+        let members = Arc::downgrade(members);
+        thread::spawn(move || receive(group, sender_id, receiver, members));
+        Ok(transport)
+    }
+
+    /// Multicasts `message` as the next in this sender's sequence.
+    pub(crate) fn publish(&self, message: Message<AnonymousEncryption>) -> Result<()> {
+        let mut next_sequence = self
+            .next_sequence
+            .lock()
+            .expect("Failed to lock multicast sequence");
+        let sequence = *next_sequence;
+        let packet = serde_json::to_vec(&Packet::Data {
+            sender: self.sender_id.clone(),
+            sequence,
+            message: Box::new(message),
+        })?;
+
+        {
+            let mut history = self
+                .history
+                .lock()
+                .expect("Failed to lock multicast history");
+            history.insert(sequence, packet.clone());
+            while history.len() > HISTORY_LEN {
+                history.pop_first();
+            }
+        }
+        *next_sequence += 1;
+        // A packet lost here is recovered like one lost on the network.
+        if let Err(e) = self.socket.send_to(&packet, self.address) {
+            tracing::warn!("Failed to multicast message {}: {}", sequence, e);
+        }
+        Ok(())
+    }
+
+    fn resend(&self, sequence: u64) {
+        let history = self
+            .history
+            .lock()
+            .expect("Failed to lock multicast history");
+        let Some(packet) = history.get(&sequence) else {
+            tracing::warn!("Message {} asked for again is no longer kept", sequence);
+            return;
+        };
+        if let Err(e) = self.socket.send_to(packet, self.address) {
+            tracing::warn!("Failed to multicast message {} again: {}", sequence, e);
+        }
+    }
+
+    /// Announces the last message sent, if any was.
+    fn heartbeat(&self) {
+        let last = *self
+            .next_sequence
+            .lock()
+            .expect("Failed to lock multicast sequence")
+            - 1;
+        if last == 0 {
+            return;
+        }
+        let sent = serde_json::to_vec(&Packet::Heartbeat {
+            sender: self.sender_id.clone(),
+            last,
+        })
+        .map_err(|e| e.to_string())
+        .and_then(|packet| {
+            self.socket
+                .send_to(&packet, self.address)
+                .map_err(|e| e.to_string())
+        });
+        if let Err(e) = sent {
+            tracing::warn!("Failed to multicast heartbeat: {}", e);
+        }
+    }
+}
+
+fn timed_out(e: &std::io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+}
+
+/// Answers NACKs for `transport`'s messages and sends its heartbeats until it is dropped.
+fn serve_senders(transport: Weak<MulticastTransport>) {
+    let mut buf = vec![0; 65536];
+    let mut last_heartbeat = Instant::now();
+    while let Some(transport) = transport.upgrade() {
+        if last_heartbeat.elapsed() >= HEARTBEAT_INTERVAL {
+            transport.heartbeat();
+            last_heartbeat = Instant::now();
+        }
+        match transport.socket.recv(&mut buf) {
+            Ok(len) => match serde_json::from_slice(&buf[..len]) {
+                Ok(Packet::Nack { sender, sequence }) if sender == transport.sender_id => {
+                    transport.resend(sequence)
+                }
+                _ => tracing::warn!("Ignoring unexpected packet on multicast sender"),
+            },
+            Err(e) if timed_out(&e) => {}
+            Err(e) => {
+                tracing::error!("Multicast sender stopped answering NACKs: {}", e);
+                return;
+            }
+        }
+    }
+}
+
+/// Delivers the messages other senders multicast to the group to `members`, in each
+/// sender's order, until the members are dropped.
+fn receive(group: String, sender_id: String, socket: UdpSocket, members: Weak<Members>) {
+    let mut sequences = Sequences::<AnonymousEncryption>::new();
+    // Where to send NACKs for each sender's messages.
+    let mut senders: HashMap<String, SocketAddr> = HashMap::new();
+    let mut last_nack: Option<Instant> = None;
+    let mut buf = vec![0; 65536];
+
+    while let Some(members) = members.upgrade() {
+        match socket.recv_from(&mut buf) {
+            Ok((len, from)) => match serde_json::from_slice(&buf[..len]) {
+                Ok(Packet::Data {
+                    sender,
+                    sequence,
+                    message,
+                }) if sender != sender_id => {
+                    senders.insert(sender.clone(), from);
+                    sequences
+                        .entry(sender.clone())
+                        .or_insert_with(|| SenderSequence::starting_at(sequence));
+                    let mut delivered = VecDeque::new();
+                    sequence::deliver_numbered(
+                        &sender,
+                        sequence,
+                        *message,
+                        &mut delivered,
+                        &mut sequences,
+                    );
+                    for message in delivered {
+                        // failures are logged by fan_out; the message isn't sent again
+                        let _ = fan_out(&group, &members, message.cast());
+                    }
+                }
+                Ok(Packet::Heartbeat { sender, last }) => {
+                    // senders are only followed from the first message heard from them
+                    if let Some(progress) = sequences.get_mut(&sender) {
+                        progress.announce(last);
+                        senders.insert(sender, from);
+                    }
+                }
+                Ok(Packet::Data { .. }) => {}
+                _ => tracing::warn!("Ignoring malformed packet from {}", from),
+            },
+            Err(e) if timed_out(&e) => {}
+            Err(e) => {
+                tracing::error!("Stopped receiving multicast for {}: {}", group, e);
+                return;
+            }
+        }
+
+        let mut released = VecDeque::new();
+        if sequence::expire(
+            &mut released,
+            &mut sequences,
+            HOLD_TIMEOUT,
+            SystemTime::now(),
+        ) {
+            senders.retain(|sender, _| sequences.contains_key(sender));
+            for message in released {
+                let _ = fan_out(&group, &members, message.cast());
+            }
+        }
+
+        if last_nack.is_some_and(|at| at.elapsed() < POLL_INTERVAL) {
+            continue;
+        }
+        for (sender, progress) in &sequences {
+            for sequence in progress.missing() {
+                let nack = Packet::Nack {
+                    sender: sender.clone(),
+                    sequence,
+                };
+                let sent = serde_json::to_vec(&nack)
+                    .map_err(|e| e.to_string())
+                    .and_then(|nack| {
+                        socket
+                            .send_to(&nack, senders[sender])
+                            .map_err(|e| e.to_string())
+                    });
+                if let Err(e) = sent {
+                    tracing::warn!("Failed to ask {} for message {}: {}", sender, sequence, e);
+                }
+                last_nack = Some(Instant::now());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::multicast_group::MulticastGroup;
+    use crate::queue::QueueOps;
+    use crate::queue_builder::QueueBuilder;
+
+    fn wait_for_messages(queue: &impl QueueOps<AnonymousEncryption>, count: usize) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while queue.message_count().unwrap() < count && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn test_bound_groups_reach_each_other() {
+        let address = SocketAddrV4::new(Ipv4Addr::new(239, 255, 0, 1), 9601);
+        let head_office = MulticastGroup::bind("prices", address, Ipv4Addr::LOCALHOST).unwrap();
+        let branch = MulticastGroup::bind("prices", address, Ipv4Addr::LOCALHOST).unwrap();
+        let mut shop = QueueBuilder::new("shop").build();
+        shop.join_group(&branch).unwrap();
+
+        head_office.send(Message::new("Price update")).unwrap();
+        head_office.send(Message::new("Second update")).unwrap();
+
+        wait_for_messages(&shop, 2);
+        assert_eq!(shop.receive().unwrap().content(), "Price update");
+        assert_eq!(shop.receive().unwrap().content(), "Second update");
+    }
+
+    #[test]
+    fn test_lost_message_is_sent_again() {
+        let address = SocketAddrV4::new(Ipv4Addr::new(239, 255, 0, 2), 9602);
+        let group = MulticastGroup::bind("prices", address, Ipv4Addr::LOCALHOST).unwrap();
+        let mut shop = QueueBuilder::new("shop").build();
+        shop.join_group(&group).unwrap();
+
+        // a sender whose second message never arrives
+        let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        sender
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let data = |sequence: u64| {
+            serde_json::to_vec(&Packet::Data {
+                sender: "head-office".to_string(),
+                sequence,
+                message: Box::new(Message::new(&format!("Update {}", sequence))),
+            })
+            .unwrap()
+        };
+        sender.send_to(&data(1), address).unwrap();
+        sender.send_to(&data(3), address).unwrap();
+
+        let mut buf = vec![0; 65536];
+        let len = sender.recv(&mut buf).unwrap();
+        let Packet::Nack {
+            sender: nacked,
+            sequence,
+        } = serde_json::from_slice(&buf[..len]).unwrap()
+        else {
+            panic!("expected a NACK");
+        };
+        assert_eq!((nacked.as_str(), sequence), ("head-office", 2));
+        wait_for_messages(&shop, 1);
+        assert_eq!(shop.message_count().unwrap(), 1);
+
+        sender.send_to(&data(2), address).unwrap();
+        wait_for_messages(&shop, 3);
+        let received: Vec<_> = (0..3)
+            .map(|_| shop.receive().unwrap().content().to_string())
+            .collect();
+        assert_eq!(received, ["Update 1", "Update 2", "Update 3"]);
+    }
+
+    #[test]
+    fn test_lost_last_message_is_noticed_from_heartbeat() {
+        let address = SocketAddrV4::new(Ipv4Addr::new(239, 255, 0, 3), 9603);
+        let group = MulticastGroup::bind("prices", address, Ipv4Addr::LOCALHOST).unwrap();
+        let mut shop = QueueBuilder::new("shop").build();
+        shop.join_group(&group).unwrap();
+
+        // a sender whose latest message never arrives, so no later one reveals the gap
+        let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        sender
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let send = |packet: Packet| {
+            sender
+                .send_to(&serde_json::to_vec(&packet).unwrap(), address)
+                .unwrap()
+        };
+        let data = |sequence: u64| Packet::Data {
+            sender: "head-office".to_string(),
+            sequence,
+            message: Box::new(Message::new(&format!("Update {}", sequence))),
+        };
+        send(data(1));
+        wait_for_messages(&shop, 1);
+        send(Packet::Heartbeat {
+            sender: "head-office".to_string(),
+            last: 2,
+        });
+
+        let mut buf = vec![0; 65536];
+        let len = sender.recv(&mut buf).unwrap();
+        let Packet::Nack {
+            sender: nacked,
+            sequence,
+        } = serde_json::from_slice(&buf[..len]).unwrap()
+        else {
+            panic!("expected a NACK");
+        };
+        assert_eq!((nacked.as_str(), sequence), ("head-office", 2));
+
+        send(data(2));
+        wait_for_messages(&shop, 2);
+        assert_eq!(shop.receive().unwrap().content(), "Update 1");
+        assert_eq!(shop.receive().unwrap().content(), "Update 2");
+    }
+
+    #[test]
+    fn test_message_that_never_arrives_is_given_up_on() {
+        let address = SocketAddrV4::new(Ipv4Addr::new(239, 255, 0, 4), 9604);
+        let group = MulticastGroup::bind("prices", address, Ipv4Addr::LOCALHOST).unwrap();
+        let mut shop = QueueBuilder::new("shop").build();
+        shop.join_group(&group).unwrap();
+
+        // a sender that died before sending its second message again
+        let sender = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        sender.set_nonblocking(true).unwrap();
+        let data = |sequence: u64| {
+            serde_json::to_vec(&Packet::Data {
+                sender: "head-office".to_string(),
+                sequence,
+                message: Box::new(Message::new(&format!("Update {}", sequence))),
+            })
+            .unwrap()
+        };
+        sender.send_to(&data(1), address).unwrap();
+        sender.send_to(&data(3), address).unwrap();
+        wait_for_messages(&shop, 1);
+        assert_eq!(shop.message_count().unwrap(), 1);
+
+        let deadline = Instant::now() + HOLD_TIMEOUT + Duration::from_secs(2);
+        while shop.message_count().unwrap() < 2 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(shop.receive().unwrap().content(), "Update 1");
+        assert_eq!(shop.receive().unwrap().content(), "Update 3");
+
+        // the NACKs stop once the message is given up on
+        let mut buf = vec![0; 65536];
+        thread::sleep(POLL_INTERVAL * 2);
+        while sender.recv(&mut buf).is_ok() {}
+        thread::sleep(POLL_INTERVAL * 4);
+        while let Ok(len) = sender.recv(&mut buf) {
+            assert!(!matches!(
+                serde_json::from_slice(&buf[..len]),
+                Ok(Packet::Nack { .. })
+            ));
+        }
+    }
+}
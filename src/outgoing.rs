@@ -0,0 +1,506 @@
+use crate::directory::Directory;
+use crate::error::MSMQError;
+use crate::features::AnonymousEncryption;
+use crate::message::Message;
+use crate::multicast_group::GroupMember;
+use crate::queue::{Queue, QueueOps};
+use crate::queue_builder::QueueBuilder;
+use crate::{ReceivedMessage, Response, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+/// Wait after the first failed attempt to reach a remote server; it doubles with every
+/// further failure up to `MAX_BACKOFF`.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+/// How long to wait for a remote server to connect and to answer.
+const REMOTE_TIMEOUT: Duration = Duration::from_secs(5);
+/// Subqueue of an outgoing queue holding the messages the remote server refused.
+pub const REJECTED_SUBQUEUE: &str = "rejected";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DestinationState {
+    /// The last attempt to forward a message reached the remote server.
+    Connected,
+    /// The last `failed_attempts` attempts failed, because the remote server couldn't be
+    /// reached or couldn't store the message just then; the next one is made after a
+    /// back-off.
+    Waiting { failed_attempts: u32 },
+    /// Forwarding was paused; messages keep being stored until it is resumed.
+    Paused,
+}
+
+/// State and backlog of the outgoing queue for one remote server.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutgoingStatus {
+    pub address: String,
+    pub state: DestinationState,
+    /// Messages stored and not yet forwarded.
+    pub pending: usize,
+    /// Messages the remote server refused, set aside in the [`REJECTED_SUBQUEUE`].
+    pub rejected: usize,
+}
+
+#[derive(Default)]
+struct Status {
+    connected: bool,
+    failed_attempts: u32,
+    paused: bool,
+    closed: bool,
+}
+
+/// Messages stored for one remote server, forwarded by a thread of their own.
+struct OutgoingQueue {
+    address: String,
+    queue: Queue,
+    status: Mutex<Status>,
+    /// Wakes the forwarder when a message is stored, forwarding is resumed or the queue is
+    /// closed.
+    wake: Condvar,
+}
+
+impl OutgoingQueue {
+    fn state(&self) -> DestinationState {
+        let status = self.status.lock().expect("Failed to lock outgoing status");
+        if status.paused {
+            DestinationState::Paused
+        } else if status.connected {
+            DestinationState::Connected
+        } else {
+            DestinationState::Waiting {
+                failed_attempts: status.failed_attempts,
+            }
+        }
+    }
+
+    fn update(&self, change: impl FnOnce(&mut Status)) {
+        change(&mut self.status.lock().expect("Failed to lock outgoing status"));
+        self.wake.notify_all();
+    }
+
+    /// Forwards stored messages in order until the queue is closed, backing off while the
+    /// remote server can't be reached or fails to store a message. Only messages it refuses
+    /// outright are set aside.
+    fn forward(&self) {
+        let mut queue = self.queue.clone();
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            let mut status = self.status.lock().expect("Failed to lock outgoing status");
+            if status.closed {
+                return;
+            }
+            // forwarded whether or not it is due, as the remote queue holds it back itself
+            let next = queue
+                .queue
+                .lock()
+                .expect("Failed to lock the queue")
+                .front()
+                .cloned();
+            let Some(message) = next.filter(|_| !status.paused) else {
+                drop(self.wake.wait(status));
+                continue;
+            };
+            drop(status);
+
+            let failure = match self.deliver_remote(message.clone()) {
+                Ok(Response::Rejected { message: reason }) => {
+                    tracing::warn!(
+                        "{} refused message {}: {}",
+                        self.address,
+                        message.id(),
+                        reason
+                    );
+                    if let Err(e) = queue.move_to_subqueue(message.id(), REJECTED_SUBQUEUE) {
+                        tracing::error!("Failed to set aside message {}: {}", message.id(), e);
+                    }
+                    None
+                }
+                Ok(Response::Error { message: reason }) => Some(reason),
+                Ok(_) => {
+                    queue.remove(message.id());
+                    None
+                }
+                Err(e) => Some(e.to_string()),
+            };
+
+            match failure {
+                None => {
+                    backoff = INITIAL_BACKOFF;
+                    self.update(|status| {
+                        status.connected = true;
+                        status.failed_attempts = 0;
+                    });
+                }
+                Some(reason) => {
+                    tracing::warn!(
+                        "Failed to forward to {}, retrying in {:?}: {}",
+                        self.address,
+                        backoff,
+                        reason
+                    );
+                    let mut status = self.status.lock().expect("Failed to lock outgoing status");
+                    status.connected = false;
+                    status.failed_attempts += 1;
+                    let status = self
+                        .wake
+                        .wait_timeout_while(status, backoff, |status| !status.closed)
+                        .expect("Failed to lock outgoing status");
+                    drop(status);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+
+    /// Sends `message` to the remote server. The server drops repeats of a message it
+    /// already has, so a message whose answer was lost can safely be sent again.
+    fn deliver_remote(&self, message: Message<AnonymousEncryption>) -> Result<Response> {
+        let address = self
+            .address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| MSMQError::Custom(format!("Can't resolve {}", self.address)))?;
+        let mut stream = TcpStream::connect_timeout(&address, REMOTE_TIMEOUT)?;
+        stream.set_read_timeout(Some(REMOTE_TIMEOUT))?;
+        let request = ReceivedMessage::EnqueueMessage { message };
+        stream.write_all(&serde_json::to_vec(&request)?)?;
+
+        let response = serde_json::Deserializer::from_reader(&stream)
+            .into_iter()
+            .next()
+            .ok_or_else(|| {
+                MSMQError::Custom(format!("{} closed the connection", self.address))
+            })??;
+        Ok(response)
+    }
+}
+
+impl GroupMember for OutgoingQueue {
+    fn deliver(&self, message: Message) -> Result<()> {
+        self.queue.send_checked(message.cast())?;
+        self.update(|_| {});
+        Ok(())
+    }
+
+    fn member_id(&self) -> usize {
+        self as *const Self as usize
+    }
+}
+
+/// Store-and-forward queues for messages to queues on other [`QueueServer`]s, one per
+/// remote server. A message sent to a remote server is persisted first and forwarded once
+/// the server can be reached, in the order messages were sent. Messages the remote server
+/// refuses are set aside in the [`REJECTED_SUBQUEUE`] of the outgoing queue; other errors
+/// are retried like an unreachable server.
+///
+/// [`QueueServer`]: crate::run_server
+pub struct OutgoingQueues {
+    dir: PathBuf,
+    /// Outgoing queues keyed by remote address.
+    queues: Mutex<HashMap<String, Arc<OutgoingQueue>>>,
+    /// Threads forwarding the outgoing queues, joined when these are dropped.
+    forwarders: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl OutgoingQueues {
+    /// Opens the outgoing queues stored in `dir` and starts forwarding what they hold.
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let outgoing = Self {
+            dir,
+            queues: Mutex::new(HashMap::new()),
+            forwarders: Mutex::new(Vec::new()),
+        };
+        for entry in fs::read_dir(&outgoing.dir)? {
+            let path = entry?.path();
+            if path
+                .extension()
+                .is_some_and(|extension| extension == "outgoing")
+            {
+                let file_stem = path.file_stem().unwrap_or_default().to_string_lossy();
+                outgoing.queue(&address_of(&file_stem))?;
+            }
+        }
+        Ok(outgoing)
+    }
+
+    /// Stores `message` for the queue server listening on `address`.
+    pub fn send(&self, address: &str, message: Message) -> Result<()> {
+        self.queue(address)?.deliver(message)
+    }
+
+    /// Makes the server listening on `address` reachable through `directory` under `name`,
+    /// so aliases, distribution lists and routes can point at it.
+    pub fn register(&self, name: &str, address: &str, directory: &Directory) -> Result<()> {
+        directory.register_remote(name, address, self.queue(address)?)
+    }
+
+    /// Registers the servers `directory` remembers again, e.g. after a restart.
+    pub fn register_saved(&self, directory: &Directory) -> Result<()> {
+        for (name, address) in directory.remotes()? {
+            directory.register(&name, self.queue(&address)?)?;
+        }
+        Ok(())
+    }
+
+    /// State of every outgoing queue, ordered by address.
+    pub fn status(&self) -> Vec<OutgoingStatus> {
+        let mut status: Vec<_> = self
+            .queues
+            .lock()
+            .expect("Failed to lock outgoing queues")
+            .values()
+            .map(|queue| OutgoingStatus {
+                address: queue.address.clone(),
+                state: queue.state(),
+                pending: queue.queue.message_count().unwrap_or_default(),
+                rejected: queue.queue.subqueue_count(REJECTED_SUBQUEUE),
+            })
+            .collect();
+        status.sort_by(|a, b| a.address.cmp(&b.address));
+        status
+    }
+
+    /// Stops forwarding to `address` until [`resume`](OutgoingQueues::resume) is called.
+    pub fn pause(&self, address: &str) -> Result<()> {
+        self.existing(address)?
+            .update(|status| status.paused = true);
+        Ok(())
+    }
+
+    pub fn resume(&self, address: &str) -> Result<()> {
+        self.existing(address)?
+            .update(|status| status.paused = false);
+        Ok(())
+    }
+
+    fn existing(&self, address: &str) -> Result<Arc<OutgoingQueue>> {
+        self.queues
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?
+            .get(address)
+            .cloned()
+            .ok_or_else(|| MSMQError::QueueNotFound(address.to_string()))
+    }
+
+    /// The outgoing queue for `address`, opened and started if there isn't one yet.
+    fn queue(&self, address: &str) -> Result<Arc<OutgoingQueue>> {
+        let mut queues = self
+            .queues
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?;
+        if let Some(queue) = queues.get(address) {
+            return Ok(Arc::clone(queue));
+        }
+
+        let path = self.dir.join(format!("{}.outgoing", file_stem_of(address)));
+        let queue = Arc::new(OutgoingQueue {
+            address: address.to_string(),
+            queue: QueueBuilder::new(address)
+                .with_persistence(path)
+                .try_build()?,
+            status: Mutex::new(Status::default()),
+            wake: Condvar::new(),
+        });
+        let forwarder = Arc::clone(&queue);
+        self.forwarders
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?
+            .push(thread::spawn(move || forwarder.forward()));
+        queues.insert(address.to_string(), Arc::clone(&queue));
+        Ok(queue)
+    }
+}
+
+impl Drop for OutgoingQueues {
+    fn drop(&mut self) {
+        if let Ok(queues) = self.queues.lock() {
+            for queue in queues.values() {
+                queue.update(|status| status.closed = true);
+            }
+        }
+        if let Ok(forwarders) = self.forwarders.get_mut() {
+            for forwarder in forwarders.drain(..) {
+                if forwarder.join().is_err() {
+                    tracing::error!("An outgoing queue forwarder panicked");
+                }
+            }
+        }
+    }
+}
+
+/// Name of the file holding the outgoing queue for `address`, without `:` so it's valid
+/// on every platform.
+fn file_stem_of(address: &str) -> String {
+    match address.rsplit_once(':') {
+        Some((host, port)) => format!("{}_{}", host, port),
+        None => address.to_string(),
+    }
+}
+
+fn address_of(file_stem: &str) -> String {
+    match file_stem.rsplit_once('_') {
+        Some((host, port)) => format!("{}:{}", host, port),
+        None => file_stem.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::run_server;
+    use std::time::Instant;
+
+    fn wait_until(condition: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !condition() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    fn dequeue(address: &str) -> Option<String> {
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream
+            .write_all(&serde_json::to_vec(&ReceivedMessage::Dequeue).unwrap())
+            .unwrap();
+        match serde_json::Deserializer::from_reader(stream)
+            .into_iter()
+            .next()
+            .unwrap()
+            .unwrap()
+        {
+            Response::Dequeued { content } => Some(content),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn test_messages_wait_for_remote_and_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let remote = "127.0.0.1:8021";
+        {
+            let outgoing = OutgoingQueues::open(dir.path().join("outgoing")).unwrap();
+            outgoing.send(remote, Message::new("First")).unwrap();
+            outgoing.send(remote, Message::new("Second")).unwrap();
+            wait_until(|| {
+                outgoing.status()[0].state != DestinationState::Waiting { failed_attempts: 0 }
+            });
+            let status = &outgoing.status()[0];
+            assert!(matches!(status.state, DestinationState::Waiting { .. }));
+            assert_eq!(status.pending, 2);
+        }
+
+        let outgoing = OutgoingQueues::open(dir.path().join("outgoing")).unwrap();
+        assert_eq!(outgoing.status()[0].address, remote);
+        let queue_path = dir.path().join("orders.msmq").to_string_lossy().to_string();
+        thread::spawn(move || run_server(&queue_path, remote).unwrap());
+
+        wait_until(|| outgoing.status()[0].pending == 0);
+        assert_eq!(outgoing.status()[0].state, DestinationState::Connected);
+        assert_eq!(dequeue(remote).as_deref(), Some("First"));
+        assert_eq!(dequeue(remote).as_deref(), Some("Second"));
+    }
+
+    #[test]
+    fn test_only_refused_messages_are_set_aside() {
+        let remote = "127.0.0.1:8028";
+        let listener = std::net::TcpListener::bind(remote).unwrap();
+        let received = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&received);
+        thread::spawn(move || {
+            let responses = [
+                Response::Error {
+                    message: "Io Error: disk full".to_string(),
+                },
+                Response::Success,
+                Response::Rejected {
+                    message: "Queue orders is transactional".to_string(),
+                },
+            ];
+            for response in responses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let request = serde_json::Deserializer::from_reader(&stream)
+                    .into_iter()
+                    .next()
+                    .unwrap()
+                    .unwrap();
+                if let ReceivedMessage::EnqueueMessage { message } = request {
+                    seen.lock().unwrap().push(message.content().to_string());
+                }
+                stream
+                    .write_all(&serde_json::to_vec(&response).unwrap())
+                    .unwrap();
+            }
+        });
+
+        let dir = tempfile::tempdir().unwrap();
+        let outgoing = OutgoingQueues::open(dir.path().join("outgoing")).unwrap();
+        outgoing.send(remote, Message::new("First")).unwrap();
+        outgoing.send(remote, Message::new("Second")).unwrap();
+        wait_until(|| outgoing.status()[0].pending == 0);
+        let status = &outgoing.status()[0];
+        assert_eq!(status.state, DestinationState::Connected);
+        assert_eq!(status.rejected, 1);
+        assert_eq!(*received.lock().unwrap(), ["First", "First", "Second"]);
+
+        let queue = outgoing.existing(remote).unwrap();
+        drop(outgoing);
+        assert_eq!(Arc::strong_count(&queue), 1);
+    }
+
+    #[test]
+    fn test_delayed_message_is_forwarded_in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let remote = "127.0.0.1:8029";
+        let queue_path = dir.path().join("orders.msmq").to_string_lossy().to_string();
+        thread::spawn(move || run_server(&queue_path, remote).unwrap());
+        thread::sleep(Duration::from_millis(100));
+
+        let outgoing = OutgoingQueues::open(dir.path().join("outgoing")).unwrap();
+        let delay = Duration::from_secs(2);
+        let sent_at = Instant::now();
+        outgoing
+            .send(remote, Message::new("Delayed").with_delay(delay))
+            .unwrap();
+        outgoing.send(remote, Message::new("Order")).unwrap();
+        wait_until(|| outgoing.status()[0].pending == 0);
+        assert_eq!(outgoing.status()[0].pending, 0);
+        assert!(sent_at.elapsed() < delay);
+        assert_eq!(dequeue(remote).as_deref(), Some("Order"));
+        assert_eq!(dequeue(remote), None);
+
+        thread::sleep(delay.saturating_sub(sent_at.elapsed()));
+        assert_eq!(dequeue(remote).as_deref(), Some("Delayed"));
+    }
+
+    #[test]
+    fn test_paused_destination_holds_messages() {
+        let dir = tempfile::tempdir().unwrap();
+        let remote = "127.0.0.1:8022";
+        let queue_path = dir.path().join("orders.msmq").to_string_lossy().to_string();
+        thread::spawn(move || run_server(&queue_path, remote).unwrap());
+        thread::sleep(Duration::from_millis(100));
+
+        let outgoing = OutgoingQueues::open(dir.path().join("outgoing")).unwrap();
+        let directory = Directory::new();
+        outgoing.register("orders", remote, &directory).unwrap();
+        outgoing.pause(remote).unwrap();
+        directory.send("orders", Message::new("Order")).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(outgoing.status()[0].state, DestinationState::Paused);
+        assert_eq!(outgoing.status()[0].pending, 1);
+        assert_eq!(dequeue(remote), None);
+
+        outgoing.resume(remote).unwrap();
+        wait_until(|| outgoing.status()[0].pending == 0);
+        assert_eq!(dequeue(remote).as_deref(), Some("Order"));
+    }
+}
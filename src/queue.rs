@@ -1,10 +1,22 @@
 use crate::{
-    distributed_transaction::DistributedTransaction, error::MSMQError, features::*,
-    message::Message, multicast_group::MulticastGroup, Result,
+    dedup::DeduplicationWindow,
+    directory::Directory,
+    distributed_transaction::DistributedTransaction,
+    error::MSMQError,
+    features::*,
+    message::Message,
+    multicast_group::{GroupMember, MulticastGroup},
+    selector::Selector,
+    sequence::{self, Sequences},
+    storage::{JournalRecord, QueueSnapshot, QueueStorage},
+    topic::TopicExchange,
+    transaction_log::TransactionLog,
+    Result,
 };
 use std::{
-    collections::VecDeque,
-    sync::{Arc, Mutex},
+    collections::{HashMap, HashSet, VecDeque},
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant, SystemTime},
 };
 
 pub trait QueueOps<E>: Send + Sync
@@ -12,18 +24,45 @@ where
     E: EncryptFeature,
 {
     fn send(&mut self, message: Message<E>) -> Result<()>;
+    fn send_batch(&mut self, messages: Vec<Message<E>>) -> Result<()>;
     fn send_distributed_transactional(
         &mut self,
         message: Message<E>,
         distributed_transaction: &DistributedTransaction,
     ) -> Result<()>;
     fn receive(&mut self) -> Option<Message<E>>;
+    /// Receives up to `max` messages, waiting at most `timeout` for the first one to arrive.
+    fn receive_batch(&mut self, max: usize, timeout: Duration) -> Vec<Message<E>>;
+    /// Adds the queue to `group`, so it receives a copy of every message sent to the group.
+    /// Transactional queues can't join.
     fn join_group(&mut self, group: &MulticastGroup) -> Result<()>;
+    fn leave_group(&mut self, group: &MulticastGroup) -> Result<()>;
+    /// Subscribes the queue to the topics of `exchange` matching `pattern`. Transactional
+    /// queues can't subscribe.
+    fn subscribe(&mut self, exchange: &TopicExchange, pattern: &str) -> Result<()>;
+    fn unsubscribe(&mut self, exchange: &TopicExchange, pattern: &str) -> Result<()>;
+    /// Makes the queue reachable under its name through `directory`. Transactional queues
+    /// can't register.
+    fn register(&mut self, directory: &Directory) -> Result<()>;
+    fn unregister(&mut self, directory: &Directory) -> Result<()>;
     fn message_count(&self) -> Result<usize>;
 }
 
 pub type BasicQueue<T> = Arc<Mutex<VecDeque<T>>>;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendOutcome {
+    Enqueued,
+    /// The message's dedup key was already sent within the queue's deduplication window.
+    Duplicate,
+}
+
+/// A message handed out by [`Queue::lease`] that hasn't been acknowledged yet.
+pub(crate) struct Lease<E> {
+    message: Message<E>,
+    expires_at: Instant,
+}
+
 #[derive(Clone)]
 pub struct Queue<
     J = EmptyJournal,
@@ -39,6 +78,24 @@ pub struct Queue<
     pub(crate) journaled_queue: J,
     pub(crate) dlq: D,
     pub(crate) security: E,
+    pub(crate) available: Arc<Condvar>,
+    pub(crate) leases: Arc<Mutex<HashMap<String, Lease<E>>>>,
+    pub(crate) storage: Option<Arc<QueueStorage<E>>>,
+    pub(crate) dedup: Option<Arc<DeduplicationWindow>>,
+    /// Group ids currently locked by a [`Session`](crate::session::Session).
+    pub(crate) sessions: Arc<Mutex<HashSet<String>>>,
+    pub(crate) subqueues: Arc<Mutex<HashMap<String, VecDeque<Message<E>>>>>,
+    /// Work of transactions that haven't completed yet, keyed by transaction id.
+    pub(crate) pending: Arc<Mutex<HashMap<String, PendingWork<E>>>>,
+    /// Delivery progress of senders that number their messages.
+    pub(crate) sequences: Arc<Mutex<Sequences<E>>>,
+    /// Timeout given to transactions that enlist the queue without one of their own.
+    pub(crate) transaction_timeout: Option<Duration>,
+    /// Log in which transactions that enlist the queue record their outcome.
+    pub(crate) transaction_log: Option<Arc<TransactionLog>>,
+    /// How long a sender's gap may stay open before the messages held behind it are
+    /// delivered anyway. Without one they are held until the gap fills.
+    pub(crate) sequence_hold_timeout: Option<Duration>,
     _marker: std::marker::PhantomData<(J, T, E, D)>,
 }
 
@@ -54,52 +111,687 @@ where
             journaled_queue: j,
             dlq: d,
             security: e,
+            available: Arc::new(Condvar::new()),
+            leases: Arc::new(Mutex::new(HashMap::new())),
+            storage: None,
+            dedup: None,
+            sessions: Arc::new(Mutex::new(HashSet::new())),
+            subqueues: Arc::new(Mutex::new(HashMap::new())),
+            pending: Arc::new(Mutex::new(HashMap::new())),
+            sequences: Arc::new(Mutex::new(HashMap::new())),
+            transaction_timeout: None,
+            transaction_log: None,
+            sequence_hold_timeout: None,
             _marker: std::marker::PhantomData,
         }
     }
+
+    pub(crate) fn with_deduplication(mut self, window: Duration) -> Self {
+        self.dedup = Some(Arc::new(DeduplicationWindow::new(window)));
+        self
+    }
+
+    pub(crate) fn with_transaction_timeout(mut self, timeout: Duration) -> Self {
+        self.transaction_timeout = Some(timeout);
+        self
+    }
+
+    pub(crate) fn with_transaction_log(mut self, log: Arc<TransactionLog>) -> Self {
+        self.transaction_log = Some(log);
+        self
+    }
+
+    pub(crate) fn with_sequence_hold_timeout(mut self, timeout: Duration) -> Self {
+        self.sequence_hold_timeout = Some(timeout);
+        self
+    }
+
+    /// Backs the queue with `storage`, restoring whatever it held before. Transactions that
+    /// were prepared but not completed are finished first: committed if `log` recorded
+    /// their commit, kept pending if `log` has them in doubt, rolled back otherwise.
+    pub(crate) fn with_storage(
+        mut self,
+        storage: QueueStorage<E>,
+        log: Option<&TransactionLog>,
+    ) -> Result<Self> {
+        let snapshot = storage.load()?;
+        // a new queue gets its file right away, so it is found again after a restart
+        let rewrite = !snapshot.transactions.is_empty() || !storage.exists();
+        let mut in_doubt = HashMap::new();
+        let mut completed = Vec::new();
+        {
+            let mut queue = self
+                .queue
+                .lock()
+                .map_err(|e| MSMQError::Custom(e.to_string()))?;
+            let mut sequences = self
+                .sequences
+                .lock()
+                .map_err(|e| MSMQError::Custom(e.to_string()))?;
+            sequences.extend(snapshot.sequences);
+            queue.extend(snapshot.messages);
+            for (txn_id, mut work) in snapshot.transactions {
+                if log.is_some_and(|log| log.is_committed(&txn_id)) {
+                    tracing::info!(
+                        "Completing committed transaction {} on {}",
+                        txn_id,
+                        self.name
+                    );
+                    work.apply(&mut queue, &mut sequences);
+                    completed.push(txn_id);
+                } else if log.is_some_and(|log| log.is_in_doubt(&txn_id)) {
+                    tracing::warn!("Transaction {} is in doubt on {}", txn_id, self.name);
+                    work.prepared = true;
+                    in_doubt.insert(txn_id, work);
+                } else {
+                    tracing::info!("Rolling back transaction {} on {}", txn_id, self.name);
+                    work.undo(&mut queue);
+                }
+            }
+        }
+        self.pending
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?
+            .extend(in_doubt);
+        if let Some(dedup) = &self.dedup {
+            dedup.restore(snapshot.dedup);
+        }
+        self.dlq.restore(
+            snapshot
+                .dead_letters
+                .into_iter()
+                .map(|message| message.cast())
+                .collect(),
+        );
+        self.subqueues
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?
+            .extend(
+                snapshot
+                    .subqueues
+                    .into_iter()
+                    .map(|(name, messages)| (name, messages.into())),
+            );
+        self.storage = Some(Arc::new(storage));
+        if rewrite {
+            self.compact()?;
+        }
+        if let Some(log) = log {
+            for txn_id in completed {
+                log.acknowledge(&txn_id, std::slice::from_ref(&self.name))?;
+            }
+        }
+        Ok(self)
+    }
+
+    /// Buffers `record` for the queue's storage, if it has any. Call it while holding the
+    /// lock that guards the change it describes.
+    pub(crate) fn record(&self, record: impl FnOnce() -> JournalRecord<E>) {
+        if let Some(storage) = &self.storage {
+            storage.record(record());
+        }
+    }
+
+    /// Makes the changes recorded so far durable, if the queue has storage.
+    pub(crate) fn persist(&self) -> Result<()> {
+        let Some(storage) = &self.storage else {
+            return Ok(());
+        };
+
+        storage.flush(|| self.snapshot(storage))
+    }
+
+    /// Rewrites the queue's storage, if it has any, as a single snapshot.
+    pub(crate) fn compact(&self) -> Result<()> {
+        let Some(storage) = &self.storage else {
+            return Ok(());
+        };
+
+        storage.compact(|| self.snapshot(storage))
+    }
+
+    /// Everything the queue holds. Leased messages and messages received by uncommitted
+    /// transactions are written as still queued so they are redelivered after a restart.
+    fn snapshot(&self, storage: &QueueStorage<E>) -> QueueSnapshot<E> {
+        let leases = self.leases.lock().expect("Failed to lock leases");
+        let pending = self.pending.lock().expect("Failed to lock pending work");
+        let queue = self.queue.lock().expect("Failed to lock the queue");
+        let subqueues = self.subqueues.lock().expect("Failed to lock subqueues");
+        let sequences = self.sequences.lock().expect("Failed to lock sequences");
+        let snapshot = QueueSnapshot {
+            messages: leases
+                .values()
+                .map(|lease| &lease.message)
+                .chain(
+                    pending
+                        .values()
+                        .filter(|work| !work.prepared)
+                        .flat_map(|work| work.receives.iter().map(|(_, message)| message)),
+                )
+                .chain(queue.iter())
+                .cloned()
+                .collect(),
+            dedup: self
+                .dedup
+                .as_ref()
+                .map(|dedup| dedup.snapshot())
+                .unwrap_or_default(),
+            subqueues: subqueues
+                .iter()
+                .map(|(name, messages)| (name.clone(), messages.iter().cloned().collect()))
+                .collect(),
+            sequences: sequences.clone(),
+            transactions: pending
+                .iter()
+                .filter(|(_, work)| work.prepared)
+                .map(|(txn_id, work)| (txn_id.clone(), work.clone()))
+                .collect(),
+            dead_letters: self
+                .dlq
+                .dead_lettered()
+                .into_iter()
+                .map(|message| message.cast())
+                .collect(),
+            ..Default::default()
+        };
+        // every change recorded so far is part of the snapshot
+        storage.discard_buffered();
+        snapshot
+    }
+
+    /// Sends `message` unless its dedup key was already seen within the queue's
+    /// deduplication window, reporting which of the two happened.
+    pub fn send_checked(&self, message: Message<E>) -> Result<SendOutcome>
+    where
+        T: TransactionalFeature,
+    {
+        self.check_transactional(false)?;
+        if !self.claim_dedup_key(&message) {
+            return Ok(SendOutcome::Duplicate);
+        }
+
+        let sent = vec![(message.id().to_string(), message.dedup_key().to_string())];
+        {
+            let mut queue = self
+                .queue
+                .lock()
+                .map_err(|e| MSMQError::Custom(e.to_string()))?;
+            self.record(|| JournalRecord::Enqueued(vec![message.clone()]));
+            queue.push_back(message);
+        }
+        self.available.notify_all();
+
+        let result = self.persist();
+        self.settle_sent(sent, result)
+            .map(|_| SendOutcome::Enqueued)
+    }
+
+    /// Settles the dedup keys of messages just sent once storing them succeeded or failed.
+    /// A failed send takes back the messages no one received yet, so a retry doesn't
+    /// enqueue them twice; the keys of those already received stay claimed.
+    fn settle_sent(&self, sent: Vec<(String, String)>, result: Result<()>) -> Result<()> {
+        if result.is_ok() {
+            self.settle_dedup_keys(sent.into_iter().map(|(_, key)| key), true);
+            return result;
+        }
+
+        let mut withdrawn = Vec::new();
+        let mut received = Vec::new();
+        {
+            let mut queue = self.queue.lock().expect("Failed to lock the queue");
+            for (id, key) in sent {
+                match queue.iter().position(|message| message.id() == id) {
+                    Some(position) => {
+                        queue.remove(position);
+                        withdrawn.push((id, key));
+                    }
+                    None => received.push(key),
+                }
+            }
+            if !withdrawn.is_empty() {
+                self.record(|| {
+                    JournalRecord::Removed(withdrawn.iter().map(|(id, _)| id.clone()).collect())
+                });
+            }
+        }
+        self.settle_dedup_keys(withdrawn.into_iter().map(|(_, key)| key), false);
+        self.settle_dedup_keys(received, true);
+        result
+    }
+
+    /// Claims the message's dedup key, returning `false` for a duplicate. The key is only
+    /// remembered for good by [`settle_dedup_keys`](Queue::settle_dedup_keys) once the
+    /// message is stored.
+    fn claim_dedup_key(&self, message: &Message<E>) -> bool {
+        let Some(dedup) = &self.dedup else {
+            return true;
+        };
+
+        let Some(seen_at) = dedup.claim(message.dedup_key()) else {
+            tracing::info!(
+                "Dropping duplicate message {} on queue {}",
+                message.dedup_key(),
+                self.name
+            );
+            return false;
+        };
+        // written together with the message, so the key is durable exactly when it is
+        self.record(|| JournalRecord::Deduplicated {
+            key: message.dedup_key().to_string(),
+            seen_at,
+        });
+        true
+    }
+
+    /// Remembers claimed dedup keys once their messages are `stored`, or releases them so a
+    /// retry isn't dropped as a duplicate.
+    fn settle_dedup_keys(&self, keys: impl IntoIterator<Item = String>, stored: bool) {
+        let Some(dedup) = &self.dedup else {
+            return;
+        };
+
+        for key in keys {
+            if stored {
+                dedup.confirm(&key);
+            } else {
+                dedup.release(&key);
+            }
+        }
+    }
+
+    /// Like [`persist`](Queue::persist), for callers that have no way to return the error.
+    pub(crate) fn persist_or_log(&self) {
+        if let Err(e) = self.persist() {
+            tracing::error!("Failed to persist queue {}: {}", self.name, e);
+        }
+    }
 }
 
-impl<J, T, E, D> QueueOps<E> for Queue<J, T, E, D>
+impl<J, T, E, D> Queue<J, T, E, D>
 where
     J: JournalFeature,
-    T: TransactionalFeature,
     E: EncryptFeature,
     D: DeadLetterFeature,
 {
-    fn send(&mut self, message: Message<E>) -> Result<()> {
+    /// Removes and returns the first message matching `predicate`, leaving non-matching
+    /// messages in place. Messages scheduled for later delivery are skipped.
+    pub fn receive_where<F>(&mut self, predicate: F) -> Option<Message<E>>
+    where
+        F: Fn(&Message<E>) -> bool,
+    {
+        self.receive_in_session(predicate, None)
+    }
+
+    /// Receives the first matching message. Without a `session`, grouped messages are
+    /// skipped; with one, only the head of the session's group can be received, so a head
+    /// that isn't due yet holds back the rest of its group.
+    pub(crate) fn receive_in_session<F>(
+        &mut self,
+        predicate: F,
+        session: Option<&str>,
+    ) -> Option<Message<E>>
+    where
+        F: Fn(&Message<E>) -> bool,
+    {
+        self.reclaim_expired_leases();
+        self.expire_held_sequences();
+
+        let now = SystemTime::now();
+        let result = {
+            let mut queue = self.queue.lock().expect("Failed to lock the queue");
+            let position = match session {
+                Some(group_id) => queue
+                    .iter()
+                    .position(|message| message.group_id() == Some(group_id))
+                    .filter(|&head| queue[head].is_due(now) && predicate(&queue[head]))?,
+                None => queue
+                    .iter()
+                    .position(|message| is_receivable(message, now) && predicate(message))?,
+            };
+            let message = queue.remove(position)?;
+            self.record(|| JournalRecord::Removed(vec![message.id().to_string()]));
+            Some(message)
+        };
+
+        if let Some(ref message) = result {
+            self.journaled_queue
+                .append_journal_messages(message.content());
+            self.persist_or_log();
+        }
+
+        result
+    }
+
+    /// Removes the message with `id`, whether or not it could be received right now.
+    pub(crate) fn remove(&mut self, id: &str) -> Option<Message<E>> {
+        let message = {
+            let mut queue = self.queue.lock().expect("Failed to lock the queue");
+            let position = queue.iter().position(|message| message.id() == id)?;
+            let message = queue.remove(position)?;
+            self.record(|| JournalRecord::Removed(vec![id.to_string()]));
+            message
+        };
+
+        self.journaled_queue
+            .append_journal_messages(message.content());
+        self.persist_or_log();
+        Some(message)
+    }
+
+    /// Returns a copy of the first message matching `predicate` without dequeuing it,
+    /// skipping the ones [`receive`](QueueOps::receive) wouldn't hand out either.
+    pub fn peek_where<F>(&self, predicate: F) -> Option<Message<E>>
+    where
+        F: Fn(&Message<E>) -> bool,
+    {
+        let now = SystemTime::now();
         self.queue
             .lock()
-            .map_err(|e| MSMQError::Custom(e.to_string()))?
-            .push_back(message);
+            .expect("Failed to lock the queue")
+            .iter()
+            .find(|message| is_receivable(message, now) && predicate(message))
+            .cloned()
+    }
+
+    pub fn receive_selected(&mut self, selector: &Selector) -> Option<Message<E>> {
+        self.receive_where(|message| selector.matches(message))
+    }
+
+    /// Hands out the next message without removing it for good: it has to be [`ack`]ed
+    /// before `duration` elapses, otherwise it counts as a failed delivery.
+    ///
+    /// [`ack`]: Queue::ack
+    pub fn lease(&mut self, duration: Duration) -> Option<Message<E>> {
+        self.reclaim_expired_leases();
+        self.expire_held_sequences();
+
+        let now = SystemTime::now();
+        // leases are locked before the queue, as in snapshot, so a compaction sees the
+        // message either queued or leased
+        let mut leases = self.leases.lock().expect("Failed to lock leases");
+        let mut queue = self.queue.lock().expect("Failed to lock the queue");
+        let position = queue
+            .iter()
+            .position(|message| is_receivable(message, now))?;
+        let message = queue.remove(position)?;
+        leases.insert(
+            message.id().to_string(),
+            Lease {
+                message: message.clone(),
+                expires_at: Instant::now() + duration,
+            },
+        );
+
+        Some(message)
+    }
+
+    pub fn ack(&mut self, id: &str) -> Result<()> {
+        let lease = {
+            let mut leases = self
+                .leases
+                .lock()
+                .map_err(|e| MSMQError::Custom(e.to_string()))?;
+            let lease = leases
+                .remove(id)
+                .ok_or_else(|| MSMQError::LeaseNotFound(id.to_string()))?;
+            self.record(|| JournalRecord::Removed(vec![id.to_string()]));
+            lease
+        };
+        self.journaled_queue
+            .append_journal_messages(lease.message.content());
+
+        self.persist()
+    }
+
+    pub fn nack(&mut self, id: &str) -> Result<()> {
+        {
+            let mut leases = self
+                .leases
+                .lock()
+                .map_err(|e| MSMQError::Custom(e.to_string()))?;
+            let lease = leases
+                .remove(id)
+                .ok_or_else(|| MSMQError::LeaseNotFound(id.to_string()))?;
+            self.fail_delivery(lease.message);
+        }
+
+        self.persist()
+    }
+
+    /// Treats every lease past its deadline as a failed delivery.
+    pub fn reclaim_expired_leases(&self) {
+        let now = Instant::now();
+        {
+            let mut leases = self.leases.lock().expect("Failed to lock leases");
+            let ids: Vec<String> = leases
+                .iter()
+                .filter(|(_, lease)| lease.expires_at <= now)
+                .map(|(id, _)| id.clone())
+                .collect();
+            if ids.is_empty() {
+                return;
+            }
+            // requeued while the leases are still held, so a compaction can't miss them
+            for lease in ids.iter().filter_map(|id| leases.remove(id)) {
+                self.fail_delivery(lease.message);
+            }
+        }
+        self.persist_or_log();
+    }
+
+    /// Delivers the messages held behind a sender's gap once it has stayed open past the
+    /// queue's hold timeout, and forgets senders that went quiet for as long.
+    pub(crate) fn expire_held_sequences(&self) {
+        let Some(timeout) = self.sequence_hold_timeout else {
+            return;
+        };
+        let changed = {
+            let mut queue = self.queue.lock().expect("Failed to lock the queue");
+            let mut sequences = self.sequences.lock().expect("Failed to lock sequences");
+            sequence::expire(&mut queue, &mut sequences, timeout, SystemTime::now())
+        };
 
-        Ok(())
+        if changed {
+            self.available.notify_all();
+            // rare enough that writing the whole queue out beats a journal record of its own
+            if let Err(e) = self.compact() {
+                tracing::error!("Failed to persist queue {}: {}", self.name, e);
+            }
+        }
     }
 
+    /// When the earliest outstanding lease expires, if any is outstanding.
+    fn next_lease_expiry(&self) -> Option<Instant> {
+        self.leases
+            .lock()
+            .expect("Failed to lock leases")
+            .values()
+            .map(|lease| lease.expires_at)
+            .min()
+    }
+
+    /// Puts a message back at the head of the queue, or dead-letters it once it has
+    /// exceeded the queue's max retries.
+    fn fail_delivery(&self, mut message: Message<E>) {
+        message.record_failed_delivery();
+
+        match self.dlq.max_retries() {
+            Some(max_retries) if message.delivery_count() > max_retries => {
+                tracing::warn!(
+                    "Moving message {} on queue {} to the dead-letter queue after {} failed deliveries",
+                    message.id(),
+                    self.name,
+                    message.delivery_count()
+                );
+                self.record(|| JournalRecord::DeadLettered(message.clone()));
+                self.dlq.dead_letter(message.cast());
+            }
+            _ => {
+                let mut queue = self.queue.lock().expect("Failed to lock the queue");
+                self.record(|| JournalRecord::Requeued(message.clone()));
+                queue.push_front(message);
+                drop(queue);
+                self.available.notify_all();
+            }
+        }
+    }
+}
+
+/// Whether `message` can be handed to a receiver outside of a session. Grouped messages
+/// are only handed out in order, through the session of their group.
+pub(crate) fn is_receivable<E>(message: &Message<E>, now: SystemTime) -> bool {
+    message.group_id().is_none() && message.is_due(now)
+}
+
+impl<J, T, E, D> QueueOps<E> for Queue<J, T, E, D>
+where
+    J: JournalFeature + Clone + 'static,
+    T: TransactionalFeature + Clone + 'static,
+    E: EncryptFeature + Clone,
+    D: DeadLetterFeature + Clone + 'static,
+{
+    fn send(&mut self, message: Message<E>) -> Result<()> {
+        self.send_checked(message).map(|_| ())
+    }
+
+    fn send_batch(&mut self, messages: Vec<Message<E>>) -> Result<()> {
+        self.check_transactional(false)?;
+        let messages: Vec<Message<E>> = messages
+            .into_iter()
+            .filter(|message| self.claim_dedup_key(message))
+            .collect();
+        let sent: Vec<(String, String)> = messages
+            .iter()
+            .map(|message| (message.id().to_string(), message.dedup_key().to_string()))
+            .collect();
+        {
+            let mut queue = self
+                .queue
+                .lock()
+                .map_err(|e| MSMQError::Custom(e.to_string()))?;
+            self.record(|| JournalRecord::Enqueued(messages.clone()));
+            queue.extend(messages);
+        }
+        self.available.notify_all();
+
+        let result = self.persist();
+        self.settle_sent(sent, result)
+    }
+
+    /// Buffers `message` until `distributed_transaction` commits, enlisting the queue as
+    /// one of its participants.
     fn send_distributed_transactional(
         &mut self,
         message: Message<E>,
         distributed_transaction: &DistributedTransaction,
     ) -> Result<()> {
-        Ok(())
+        self.check_transactional(true)?;
+        if let Some(timeout) = self.transaction_timeout {
+            distributed_transaction.set_default_timeout(timeout);
+        }
+        distributed_transaction.enlist_with(&self.name, Arc::new(self.clone()), || {
+            self.pending
+                .lock()
+                .map_err(|e| MSMQError::Custom(e.to_string()))?
+                .entry(distributed_transaction.id().to_string())
+                .or_default()
+                .sends
+                .push(message);
+            Ok(())
+        })
     }
 
     fn receive(&mut self) -> Option<Message<E>> {
-        let result = self
-            .queue
-            .lock()
-            .expect("Failed to lock the queue")
-            .pop_front();
+        self.receive_where(|_| true)
+    }
 
-        if let Some(ref message) = result {
-            self.journaled_queue
-                .append_journal_messages(message.content());
+    fn receive_batch(&mut self, max: usize, timeout: Duration) -> Vec<Message<E>> {
+        let deadline = Instant::now() + timeout;
+        let messages = loop {
+            self.reclaim_expired_leases();
+            self.expire_held_sequences();
+            let next_expiry = self.next_lease_expiry();
+
+            let mut queue = self.queue.lock().expect("Failed to lock the queue");
+            let now = SystemTime::now();
+            let mut messages = Vec::new();
+            let mut index = 0;
+            while messages.len() < max && index < queue.len() {
+                if is_receivable(&queue[index], now) {
+                    messages.extend(queue.remove(index));
+                } else {
+                    index += 1;
+                }
+            }
+            if !messages.is_empty() {
+                self.record(|| {
+                    JournalRecord::Removed(
+                        messages
+                            .iter()
+                            .map(|message| message.id().to_string())
+                            .collect(),
+                    )
+                });
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if !messages.is_empty() || remaining.is_zero() {
+                break messages;
+            }
+
+            // wake up for whichever comes first: the deadline or the next scheduled message
+            let next_due = queue
+                .iter()
+                .filter_map(|message| message.not_before())
+                .min()
+                .map(|at| at.duration_since(now).unwrap_or_default());
+            let wait = next_due
+                .into_iter()
+                .chain(next_expiry.map(|at| at.saturating_duration_since(Instant::now())))
+                .fold(remaining, Duration::min);
+            drop(
+                self.available
+                    .wait_timeout(queue, wait)
+                    .expect("Failed to lock the queue"),
+            );
+        };
+
+        if !messages.is_empty() {
+            for message in &messages {
+                self.journaled_queue
+                    .append_journal_messages(message.content());
+            }
+            self.persist_or_log();
         }
 
-        result
+        messages
     }
 
     fn join_group(&mut self, group: &MulticastGroup) -> Result<()> {
-        Ok(())
+        self.check_transactional(false)?;
+        group.join(&self.name, Arc::new(self.clone()))
+    }
+
+    fn leave_group(&mut self, group: &MulticastGroup) -> Result<()> {
+        group.leave(&self.name)
+    }
+
+    fn subscribe(&mut self, exchange: &TopicExchange, pattern: &str) -> Result<()> {
+        self.check_transactional(false)?;
+        exchange.subscribe(&self.name, pattern, Arc::new(self.clone()))
+    }
+
+    fn unsubscribe(&mut self, exchange: &TopicExchange, pattern: &str) -> Result<()> {
+        exchange.unsubscribe(&self.name, pattern)
+    }
+
+    fn register(&mut self, directory: &Directory) -> Result<()> {
+        self.check_transactional(false)?;
+        directory.register(&self.name, Arc::new(self.clone()))
+    }
+
+    fn unregister(&mut self, directory: &Directory) -> Result<()> {
+        directory.unregister(&self.name)
     }
 
     fn message_count(&self) -> Result<usize> {
@@ -111,6 +803,23 @@ where
     }
 }
 
+impl<J, T, E, D> GroupMember for Queue<J, T, E, D>
+where
+    J: Send + Sync,
+    T: TransactionalFeature,
+    E: EncryptFeature,
+    D: DeadLetterFeature,
+{
+    fn deliver(&self, message: Message) -> Result<()> {
+        self.send_checked(message.cast()).map(|_| ())
+    }
+
+    /// Clones of a queue share their state, so they are the same member.
+    fn member_id(&self) -> usize {
+        Arc::as_ptr(&self.queue) as usize
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -134,4 +843,157 @@ mod tests {
         assert!(received.is_some());
         assert_eq!(received.unwrap().content(), "Test message");
     }
+
+    #[test]
+    fn test_receive_where_skips_non_matching() {
+        let mut queue = QueueBuilder::new("test_queue").build();
+        queue
+            .send(Message::new("Low").with_label("orders").with_priority(1))
+            .unwrap();
+        queue
+            .send(Message::new("High").with_label("orders").with_priority(6))
+            .unwrap();
+
+        assert!(queue.peek_where(|m| m.label() == "invoices").is_none());
+
+        let selector = Selector::parse("label = 'orders' AND priority > 3").unwrap();
+        let received = queue.receive_selected(&selector).unwrap();
+        assert_eq!(received.content(), "High");
+        assert_eq!(queue.message_count().unwrap(), 1);
+        assert_eq!(queue.receive().unwrap().content(), "Low");
+    }
+
+    #[test]
+    fn test_peek_skips_grouped_messages_like_receive() {
+        let mut queue = QueueBuilder::new("test_queue").build();
+        queue
+            .send(Message::new("Grouped").with_group_id("order-1"))
+            .unwrap();
+        queue.send(Message::new("Plain")).unwrap();
+
+        assert_eq!(queue.peek_where(|_| true).unwrap().content(), "Plain");
+        assert_eq!(queue.receive().unwrap().content(), "Plain");
+        assert!(queue.peek_where(|_| true).is_none());
+    }
+
+    #[test]
+    fn test_send_and_receive_batch() {
+        let mut queue = QueueBuilder::new("test_queue").build();
+        let messages = (0..5).map(|i| Message::new(&i.to_string())).collect();
+        queue.send_batch(messages).unwrap();
+
+        let received = queue.receive_batch(3, Duration::from_millis(10));
+        let contents: Vec<_> = received.iter().map(|m| m.content().as_str()).collect();
+        assert_eq!(contents, ["0", "1", "2"]);
+        assert_eq!(queue.receive_batch(10, Duration::from_millis(10)).len(), 2);
+        assert!(queue
+            .receive_batch(10, Duration::from_millis(10))
+            .is_empty());
+    }
+
+    #[test]
+    fn test_receive_batch_waits_for_messages() {
+        let mut queue = QueueBuilder::new("test_queue").build();
+        let mut sender = queue.clone();
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            sender.send(Message::new("Late message")).unwrap();
+        });
+
+        let received = queue.receive_batch(10, Duration::from_secs(5));
+        handle.join().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].content(), "Late message");
+    }
+
+    #[test]
+    fn test_delayed_message_is_invisible_until_due() {
+        let mut queue = QueueBuilder::new("test_queue").build();
+        queue
+            .send(Message::new("Delayed").with_delay(Duration::from_millis(100)))
+            .unwrap();
+        queue.send(Message::new("Immediate")).unwrap();
+
+        assert_eq!(queue.receive().unwrap().content(), "Immediate");
+        assert!(queue.receive().is_none());
+        assert_eq!(queue.message_count().unwrap(), 1);
+
+        let received = queue.receive_batch(1, Duration::from_secs(5));
+        assert_eq!(received[0].content(), "Delayed");
+    }
+
+    #[test]
+    fn test_scheduled_message_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("scheduled.msmq");
+        let due = SystemTime::now() + Duration::from_millis(100);
+
+        {
+            let mut queue = QueueBuilder::new("scheduled")
+                .with_persistence(&path)
+                .build();
+            queue
+                .send(Message::new("Reminder").with_not_before(due))
+                .unwrap();
+        }
+
+        let mut queue = QueueBuilder::new("scheduled")
+            .with_persistence(&path)
+            .build();
+        assert_eq!(queue.message_count().unwrap(), 1);
+        assert!(queue.receive().is_none());
+
+        std::thread::sleep(due.duration_since(SystemTime::now()).unwrap_or_default());
+        assert_eq!(queue.receive().unwrap().content(), "Reminder");
+
+        let queue = QueueBuilder::new("scheduled")
+            .with_persistence(&path)
+            .build();
+        assert_eq!(queue.message_count().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_dedup_window_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("orders.msmq");
+        let build = || {
+            QueueBuilder::new("orders")
+                .with_deduplication(Duration::from_secs(60))
+                .with_persistence(&path)
+                .build()
+        };
+        let message = Message::new("Order").with_dedup_key("order-1");
+
+        let queue = build();
+        assert_eq!(
+            queue.send_checked(message.clone()).unwrap(),
+            SendOutcome::Enqueued
+        );
+        drop(queue);
+
+        let queue = build();
+        assert_eq!(queue.send_checked(message).unwrap(), SendOutcome::Duplicate);
+        assert_eq!(queue.message_count().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_send_that_failed_to_persist_can_be_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage_dir = dir.path().join("storage");
+        std::fs::create_dir(&storage_dir).unwrap();
+        let queue = QueueBuilder::new("orders")
+            .with_deduplication(Duration::from_secs(60))
+            .with_persistence(storage_dir.join("orders.msmq"))
+            .build();
+        let message = Message::new("Order").with_dedup_key("order-1");
+
+        std::fs::remove_dir_all(&storage_dir).unwrap();
+        assert!(queue.send_checked(message.clone()).is_err());
+
+        assert_eq!(queue.message_count().unwrap(), 0);
+
+        std::fs::create_dir(&storage_dir).unwrap();
+        assert_eq!(queue.send_checked(message).unwrap(), SendOutcome::Enqueued);
+        assert_eq!(queue.message_count().unwrap(), 1);
+    }
 }
@@ -1,7 +1,9 @@
 use std::{
     any::Any,
     collections::HashMap,
+    path::{Path, PathBuf},
     sync::{Arc, Mutex},
+    time::Duration,
 };
 
 use lazy_static::lazy_static;
@@ -10,6 +12,9 @@ use crate::{
     features::*,
     queue::{Queue, QueueOps},
     security::Security,
+    storage::QueueStorage,
+    transaction_log::TransactionLog,
+    Result,
 };
 
 pub struct QueueBuilder<
@@ -20,6 +25,12 @@ pub struct QueueBuilder<
 > {
     name: String,
     encryption: E,
+    dead_letter: D,
+    storage_path: Option<PathBuf>,
+    dedup_window: Option<Duration>,
+    transaction_log: Option<Arc<TransactionLog>>,
+    transaction_timeout: Option<Duration>,
+    sequence_hold_timeout: Option<Duration>,
     _marker: std::marker::PhantomData<(J, T, E, D)>,
 }
 
@@ -30,6 +41,12 @@ impl
         QueueBuilder {
             name: name.to_string(),
             encryption: AnonymousEncryption,
+            dead_letter: EmptyDeadletterQueue,
+            storage_path: None,
+            dedup_window: None,
+            transaction_log: None,
+            transaction_timeout: None,
+            sequence_hold_timeout: None,
             _marker: std::marker::PhantomData,
         }
     }
@@ -40,25 +57,71 @@ where
     J: Default + JournalFeature + Clone,
     T: TransactionalFeature,
     E: EncryptFeature + Clone,
-    D: Default + DeadLetterFeature + Clone,
+    D: DeadLetterFeature + Clone,
     Queue<J, T, E, D>: QueueOps<E>,
 {
     pub fn build(self) -> Queue<J, T, E, D> {
+        self.try_build().expect("Failed to restore persisted queue")
+    }
+
+    /// Like [`build`](QueueBuilder::build), but returns an error instead of panicking when a
+    /// persisted queue can't be restored.
+    pub fn try_build(self) -> Result<Queue<J, T, E, D>> {
         let j = J::default();
         let e = self.encryption;
-        let d = D::default();
+        let d = self.dead_letter;
 
-        let queue = Queue::new(&self.name, j.clone(), e.clone(), d.clone());
+        let mut queue = Queue::new(&self.name, j.clone(), e.clone(), d.clone());
+        if let Some(window) = self.dedup_window {
+            queue = queue.with_deduplication(window);
+        }
+        if let Some(timeout) = self.transaction_timeout {
+            queue = queue.with_transaction_timeout(timeout);
+        }
+        if let Some(timeout) = self.sequence_hold_timeout {
+            queue = queue.with_sequence_hold_timeout(timeout);
+        }
+        if let Some(path) = self.storage_path {
+            queue = queue.with_storage(QueueStorage::new(path), self.transaction_log.as_deref())?;
+        }
+        if let Some(log) = self.transaction_log {
+            queue = queue.with_transaction_log(log);
+        }
 
         //TODO: store queue somewhere(?)
 
-        queue
+        Ok(queue)
+    }
+
+    /// Silently drops messages whose dedup key was already sent within `window`.
+    pub fn with_deduplication(mut self, window: Duration) -> Self {
+        self.dedup_window = Some(window);
+        self
+    }
+
+    /// Log consulted on startup to finish transactions that were interrupted mid-commit.
+    /// Transactions using the queue record their commit in it.
+    pub fn with_transaction_log(mut self, log: Arc<TransactionLog>) -> Self {
+        self.transaction_log = Some(log);
+        self
+    }
+
+    /// Persists the queue's messages to `path`, restoring them when the queue is rebuilt.
+    pub fn with_persistence(mut self, path: impl AsRef<Path>) -> Self {
+        self.storage_path = Some(path.as_ref().to_path_buf());
+        self
     }
 
     pub fn with_journaling(self) -> QueueBuilder<JournaledQueue<E>, T, E, D> {
         QueueBuilder {
             name: self.name,
             encryption: self.encryption,
+            dead_letter: self.dead_letter,
+            storage_path: self.storage_path,
+            dedup_window: self.dedup_window,
+            transaction_log: self.transaction_log,
+            transaction_timeout: self.transaction_timeout,
+            sequence_hold_timeout: self.sequence_hold_timeout,
             _marker: std::marker::PhantomData,
         }
     }
@@ -67,6 +130,12 @@ where
         QueueBuilder {
             name: self.name,
             encryption: self.encryption,
+            dead_letter: self.dead_letter,
+            storage_path: self.storage_path,
+            dedup_window: self.dedup_window,
+            transaction_log: self.transaction_log,
+            transaction_timeout: self.transaction_timeout,
+            sequence_hold_timeout: self.sequence_hold_timeout,
             _marker: std::marker::PhantomData,
         }
     }
@@ -75,15 +144,50 @@ where
         QueueBuilder {
             name: self.name,
             encryption: BasicEncryption(security),
+            dead_letter: self.dead_letter,
+            storage_path: self.storage_path,
+            dedup_window: self.dedup_window,
+            transaction_log: self.transaction_log,
+            transaction_timeout: self.transaction_timeout,
+            sequence_hold_timeout: self.sequence_hold_timeout,
             _marker: std::marker::PhantomData,
         }
     }
 
-    pub fn with_dlq(self) -> QueueBuilder<J, T, E, DeadletterQueue<E>> {
+    /// Adds a dead-letter queue. With `max_retries`, a message is dead-lettered once it has
+    /// failed delivery more than that many times; without, it is redelivered indefinitely.
+    pub fn with_dlq(self, max_retries: Option<u32>) -> QueueBuilder<J, T, E, DeadletterQueue<E>> {
         QueueBuilder {
             name: self.name,
             encryption: self.encryption,
+            dead_letter: DeadletterQueue {
+                max_retries,
+                ..Default::default()
+            },
+            storage_path: self.storage_path,
+            dedup_window: self.dedup_window,
+            transaction_log: self.transaction_log,
+            transaction_timeout: self.transaction_timeout,
+            sequence_hold_timeout: self.sequence_hold_timeout,
             _marker: std::marker::PhantomData,
         }
     }
 }
+
+impl<J, E, D> QueueBuilder<J, TransactionalQueue, E, D> {
+    /// Aborts transactions that use the queue once they have been running for `timeout`,
+    /// unless they were given a timeout of their own.
+    pub fn with_transaction_timeout(mut self, timeout: Duration) -> Self {
+        self.transaction_timeout = Some(timeout);
+        self
+    }
+
+    /// Gives up on a sender's missing messages once they have been missing for `timeout`:
+    /// the messages held behind them are delivered, and a missing one that turns up later
+    /// is dropped. Senders not heard from for `timeout` are forgotten, so their messages
+    /// are numbered from 1 again.
+    pub fn with_sequence_hold_timeout(mut self, timeout: Duration) -> Self {
+        self.sequence_hold_timeout = Some(timeout);
+        self
+    }
+}
@@ -0,0 +1,299 @@
+use crate::directory::Directory;
+use crate::error::MSMQError;
+use crate::message::Message;
+use crate::selector::Selector;
+use crate::storage::read_json;
+use crate::Result;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// Property naming the queue a dead-lettered copy of a message couldn't be delivered to,
+/// when the other queues of its route took theirs.
+pub const UNDELIVERED_TO_PROPERTY: &str = "undelivered_to";
+
+/// Routing rules as written in a routes file, e.g.
+///
+/// ```json
+/// {
+///     "rules": [{ "when": "label = 'orders' AND region = 'eu'", "to": "eu_orders" }],
+///     "default": "misc",
+///     "dead_letter": "unroutable"
+/// }
+/// ```
+#[derive(Deserialize, Default)]
+struct RoutesConfig {
+    #[serde(default)]
+    rules: Vec<RuleConfig>,
+    #[serde(default)]
+    default: Option<String>,
+    #[serde(default)]
+    dead_letter: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RuleConfig {
+    when: String,
+    to: String,
+}
+
+#[derive(Default)]
+struct Routes {
+    rules: Vec<(Selector, String)>,
+    default: Option<String>,
+    dead_letter: Option<String>,
+}
+
+impl TryFrom<RoutesConfig> for Routes {
+    type Error = MSMQError;
+
+    fn try_from(config: RoutesConfig) -> Result<Self> {
+        let rules = config
+            .rules
+            .into_iter()
+            .map(|rule| Ok((Selector::parse(&rule.when)?, rule.to)))
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            rules,
+            default: config.default,
+            dead_letter: config.dead_letter,
+        })
+    }
+}
+
+/// Sends messages to a destination picked by their label and properties. Rules are tried
+/// in order and the first whose selector matches wins; messages no rule matches go to the
+/// default route. Messages without a route, or whose route can't take them, go to the
+/// dead-letter route. When only some queues of a route fail, the others keep their copy and
+/// a copy for each failed queue goes to the dead-letter route, marked with
+/// [`UNDELIVERED_TO_PROPERTY`].
+///
+/// Destinations are looked up in a [`Directory`], so they may be aliases or distribution
+/// lists.
+#[derive(Clone)]
+pub struct Router {
+    directory: Directory,
+    routes: Arc<Mutex<Routes>>,
+    path: Option<PathBuf>,
+}
+
+impl Router {
+    /// A router without rules, sending everything to `default`.
+    pub fn new(directory: Directory, default: Option<&str>) -> Self {
+        Self {
+            directory,
+            routes: Arc::new(Mutex::new(Routes {
+                default: default.map(str::to_string),
+                ..Default::default()
+            })),
+            path: None,
+        }
+    }
+
+    /// Loads the rules from the routes file at `path`. A missing file means no routes.
+    pub fn load(directory: Directory, path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let routes = read_json::<RoutesConfig>(&path)?.try_into()?;
+        Ok(Self {
+            directory,
+            routes: Arc::new(Mutex::new(routes)),
+            path: Some(path),
+        })
+    }
+
+    /// Reads the routes file again. If it has an error the current rules stay in place.
+    pub fn reload(&self) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let routes = read_json::<RoutesConfig>(path)?.try_into()?;
+        *self
+            .routes
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))? = routes;
+        Ok(())
+    }
+
+    /// Sends `message` along its route, returning the destination it was sent to.
+    pub fn send(&self, message: Message) -> Result<String> {
+        let (route, dead_letter) = {
+            let routes = self
+                .routes
+                .lock()
+                .map_err(|e| MSMQError::Custom(e.to_string()))?;
+            let route = routes
+                .rules
+                .iter()
+                .find(|(selector, _)| selector.matches(&message))
+                .map(|(_, destination)| destination)
+                .or(routes.default.as_ref())
+                .cloned();
+            (route, routes.dead_letter.clone())
+        };
+
+        let Some(route) = route else {
+            let failure = MSMQError::Unroutable(message.id().to_string());
+            return self.dead_letter(dead_letter, message, failure);
+        };
+        let outcomes = match self.directory.send_each(&route, message.clone()) {
+            Ok(outcomes) => outcomes,
+            Err(e) => {
+                tracing::warn!(
+                    "Failed to route message {} to {}: {}",
+                    message.id(),
+                    route,
+                    e
+                );
+                return self.dead_letter(dead_letter, message, e);
+            }
+        };
+
+        let delivered = outcomes.iter().any(|(_, outcome)| outcome.is_ok());
+        let mut failure = None;
+        for (queue, outcome) in outcomes {
+            let Err(e) = outcome else {
+                continue;
+            };
+            if delivered {
+                // the other queues have their copy, so only this queue's is dead-lettered
+                let copy = message
+                    .clone()
+                    .with_property(UNDELIVERED_TO_PROPERTY, &queue);
+                if let Err(e) = self.dead_letter(dead_letter.clone(), copy, e) {
+                    failure = Some(e);
+                }
+            } else {
+                failure = Some(e);
+            }
+        }
+        match failure {
+            Some(e) if delivered => Err(e),
+            Some(e) => self.dead_letter(dead_letter, message, e),
+            None => Ok(route),
+        }
+    }
+
+    /// Sends `message`, which couldn't take its route because of `failure`, to the
+    /// dead-letter route, failing with `failure` if there is none.
+    fn dead_letter(
+        &self,
+        dead_letter: Option<String>,
+        message: Message,
+        failure: MSMQError,
+    ) -> Result<String> {
+        match dead_letter {
+            Some(dead_letter) => {
+                self.directory.send(&dead_letter, message)?;
+                Ok(dead_letter)
+            }
+            None => Err(failure),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::multicast_group::GroupMember;
+    use crate::queue::QueueOps;
+    use crate::queue_builder::QueueBuilder;
+    use std::fs;
+
+    #[test]
+    fn test_rules_default_and_dead_letter() {
+        let directory = Directory::new();
+        let mut orders = QueueBuilder::new("orders").build();
+        let mut unroutable = QueueBuilder::new("unroutable").build();
+        orders.register(&directory).unwrap();
+        unroutable.register(&directory).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("server.routes");
+        fs::write(
+            &path,
+            r#"{
+                "rules": [
+                    { "when": "label = 'order'", "to": "orders" },
+                    { "when": "label = 'invoice'", "to": "invoices" }
+                ],
+                "dead_letter": "unroutable"
+            }"#,
+        )
+        .unwrap();
+        let router = Router::load(directory, &path).unwrap();
+
+        let order = Message::new("Order").with_label("order");
+        assert_eq!(router.send(order).unwrap(), "orders");
+        // invoices doesn't exist and nothing matches a note
+        let invoice = Message::new("Invoice").with_label("invoice");
+        assert_eq!(router.send(invoice).unwrap(), "unroutable");
+        let note = Message::new("Note").with_label("note");
+        assert_eq!(router.send(note).unwrap(), "unroutable");
+        assert_eq!(orders.message_count().unwrap(), 1);
+        assert_eq!(unroutable.message_count().unwrap(), 2);
+
+        fs::write(&path, r#"{ "default": "orders" }"#).unwrap();
+        router.reload().unwrap();
+        let note = Message::new("Note").with_label("note");
+        assert_eq!(router.send(note).unwrap(), "orders");
+
+        fs::write(
+            &path,
+            r#"{ "rules": [{ "when": "label =", "to": "orders" }] }"#,
+        )
+        .unwrap();
+        assert!(router.reload().is_err());
+        let note = Message::new("Note").with_label("note");
+        assert_eq!(router.send(note).unwrap(), "orders");
+    }
+
+    struct Refusing;
+
+    impl GroupMember for Refusing {
+        fn deliver(&self, _message: Message) -> Result<()> {
+            Err(MSMQError::Custom("refused".to_string()))
+        }
+
+        fn member_id(&self) -> usize {
+            0
+        }
+    }
+
+    #[test]
+    fn test_only_failed_members_are_dead_lettered() {
+        let directory = Directory::new();
+        let mut orders = QueueBuilder::new("orders").build();
+        let mut unroutable = QueueBuilder::new("unroutable").build();
+        orders.register(&directory).unwrap();
+        unroutable.register(&directory).unwrap();
+        directory.register("broken", Arc::new(Refusing)).unwrap();
+        directory
+            .define_list("everyone", &["orders", "broken"])
+            .unwrap();
+        let router = Router::new(directory.clone(), Some("everyone"));
+        router.routes.lock().unwrap().dead_letter = Some("unroutable".to_string());
+
+        assert_eq!(router.send(Message::new("Order")).unwrap(), "everyone");
+        assert_eq!(orders.receive().unwrap().content(), "Order");
+        let copy = unroutable.receive().unwrap();
+        assert_eq!(copy.content(), "Order");
+        assert_eq!(copy.property(UNDELIVERED_TO_PROPERTY), Some("broken"));
+        assert!(unroutable.receive().is_none());
+
+        // nothing was delivered, so the message itself is dead-lettered
+        directory.define_list("everyone", &["broken"]).unwrap();
+        assert_eq!(router.send(Message::new("Invoice")).unwrap(), "unroutable");
+        assert_eq!(orders.message_count().unwrap(), 0);
+        let dead_lettered = unroutable.receive().unwrap();
+        assert_eq!(dead_lettered.content(), "Invoice");
+        assert_eq!(dead_lettered.property(UNDELIVERED_TO_PROPERTY), None);
+    }
+
+    #[test]
+    fn test_no_route_is_an_error() {
+        let router = Router::new(Directory::new(), None);
+        assert!(matches!(
+            router.send(Message::new("Lost")),
+            Err(MSMQError::Unroutable(_))
+        ));
+    }
+}
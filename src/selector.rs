@@ -0,0 +1,325 @@
+use crate::error::MSMQError;
+use crate::message::Message;
+use crate::Result;
+use std::cmp::Ordering;
+
+/// A parsed selector expression, e.g. `label = 'orders' AND priority >= 3`.
+///
+/// Identifiers `id`, `label`, `priority` and `correlation_id` refer to the message's own
+/// properties, any other identifier is looked up in its application properties.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Selector {
+    Compare {
+        field: String,
+        op: CompareOp,
+        value: Literal,
+    },
+    And(Box<Selector>, Box<Selector>),
+    Or(Box<Selector>, Box<Selector>),
+    Not(Box<Selector>),
+}
+
+/// The right-hand side of a comparison.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Literal {
+    /// A quoted string, always compared as a string.
+    Text(String),
+    /// An unquoted number, compared numerically with values that are finite numbers.
+    Number(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Literal(Literal),
+    Op(CompareOp),
+    And,
+    Or,
+    Not,
+    LParen,
+    RParen,
+}
+
+impl Selector {
+    pub fn parse(input: &str) -> Result<Self> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let selector = parser.parse_or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(selector),
+            Some(token) => Err(MSMQError::InvalidSelector(format!(
+                "unexpected {:?}",
+                token
+            ))),
+        }
+    }
+
+    pub fn matches<E: ?Sized>(&self, message: &Message<E>) -> bool {
+        match self {
+            Selector::Compare { field, op, value } => match field_value(message, field) {
+                Some(actual) => op.holds(compare(&actual, value)),
+                None => false,
+            },
+            Selector::And(lhs, rhs) => lhs.matches(message) && rhs.matches(message),
+            Selector::Or(lhs, rhs) => lhs.matches(message) || rhs.matches(message),
+            Selector::Not(inner) => !inner.matches(message),
+        }
+    }
+}
+
+impl CompareOp {
+    fn holds(self, ordering: Ordering) -> bool {
+        match self {
+            CompareOp::Eq => ordering == Ordering::Equal,
+            CompareOp::Ne => ordering != Ordering::Equal,
+            CompareOp::Lt => ordering == Ordering::Less,
+            CompareOp::Le => ordering != Ordering::Greater,
+            CompareOp::Gt => ordering == Ordering::Greater,
+            CompareOp::Ge => ordering != Ordering::Less,
+        }
+    }
+}
+
+fn field_value<E: ?Sized>(message: &Message<E>, field: &str) -> Option<String> {
+    match field {
+        "id" => Some(message.id().to_string()),
+        "label" => Some(message.label().to_string()),
+        "priority" => Some(message.priority().to_string()),
+        "correlation_id" => message.correlation_id().map(str::to_string),
+        _ => message.property(field).map(str::to_string),
+    }
+}
+
+/// Compares numerically when the literal is a number and both sides are finite numbers,
+/// lexically otherwise.
+fn compare(actual: &str, expected: &Literal) -> Ordering {
+    match expected {
+        Literal::Number(number) => match (finite(actual), finite(number)) {
+            (Some(a), Some(b)) => a.total_cmp(&b),
+            _ => actual.cmp(number.as_str()),
+        },
+        Literal::Text(text) => actual.cmp(text.as_str()),
+    }
+}
+
+fn finite(value: &str) -> Option<f64> {
+    value
+        .parse::<f64>()
+        .ok()
+        .filter(|number| number.is_finite())
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().peekable();
+
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::LParen);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::RParen);
+            }
+            '\'' => {
+                chars.next();
+                let mut literal = String::new();
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(c) => literal.push(c),
+                        None => {
+                            return Err(MSMQError::InvalidSelector(
+                                "unterminated string literal".to_string(),
+                            ))
+                        }
+                    }
+                }
+                tokens.push(Token::Literal(Literal::Text(literal)));
+            }
+            '=' | '!' | '<' | '>' => {
+                chars.next();
+                let followed_by_eq = chars.peek() == Some(&'=');
+                if followed_by_eq {
+                    chars.next();
+                }
+                let op = match (c, followed_by_eq) {
+                    ('=', _) => CompareOp::Eq,
+                    ('!', true) => CompareOp::Ne,
+                    ('<', false) => CompareOp::Lt,
+                    ('<', true) => CompareOp::Le,
+                    ('>', false) => CompareOp::Gt,
+                    ('>', true) => CompareOp::Ge,
+                    _ => {
+                        return Err(MSMQError::InvalidSelector(format!(
+                            "unknown operator '{}'",
+                            c
+                        )))
+                    }
+                };
+                tokens.push(Token::Op(op));
+            }
+            c if c.is_ascii_digit() || c == '-' => {
+                let mut literal = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_ascii_digit() || c == '.' || c == '-' {
+                        literal.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Literal(Literal::Number(literal)));
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut word = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_alphanumeric() || c == '_' || c == '.' {
+                        word.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(match word.to_ascii_uppercase().as_str() {
+                    "AND" => Token::And,
+                    "OR" => Token::Or,
+                    "NOT" => Token::Not,
+                    _ => Token::Ident(word),
+                });
+            }
+            c => {
+                return Err(MSMQError::InvalidSelector(format!(
+                    "unexpected character '{}'",
+                    c
+                )))
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, token: &Token) -> bool {
+        if self.tokens.get(self.pos) == Some(token) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Selector> {
+        let mut lhs = self.parse_and()?;
+        while self.eat(&Token::Or) {
+            lhs = Selector::Or(Box::new(lhs), Box::new(self.parse_and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Selector> {
+        let mut lhs = self.parse_unary()?;
+        while self.eat(&Token::And) {
+            lhs = Selector::And(Box::new(lhs), Box::new(self.parse_unary()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Selector> {
+        match self.next() {
+            Some(Token::Not) => Ok(Selector::Not(Box::new(self.parse_unary()?))),
+            Some(Token::LParen) => {
+                let inner = self.parse_or()?;
+                if !self.eat(&Token::RParen) {
+                    return Err(MSMQError::InvalidSelector("expected ')'".to_string()));
+                }
+                Ok(inner)
+            }
+            Some(Token::Ident(field)) => match (self.next(), self.next()) {
+                (Some(Token::Op(op)), Some(Token::Literal(value))) => {
+                    Ok(Selector::Compare { field, op, value })
+                }
+                _ => Err(MSMQError::InvalidSelector(format!(
+                    "expected comparison after '{}'",
+                    field
+                ))),
+            },
+            other => Err(MSMQError::InvalidSelector(format!(
+                "unexpected {:?}",
+                other
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::features::AnonymousEncryption;
+
+    #[test]
+    fn test_selector_matches_properties() {
+        let message = Message::<AnonymousEncryption>::new("Order")
+            .with_label("orders")
+            .with_priority(5)
+            .with_property("region", "eu");
+
+        let selector =
+            Selector::parse("label = 'orders' AND (priority > 3 OR region = 'us')").unwrap();
+        assert!(selector.matches(&message));
+
+        let selector = Selector::parse("NOT region = 'eu'").unwrap();
+        assert!(!selector.matches(&message));
+
+        let selector = Selector::parse("correlation_id = 'abc'").unwrap();
+        assert!(!selector.matches(&message));
+    }
+
+    #[test]
+    fn test_only_unquoted_finite_numbers_compare_numerically() {
+        let message = Message::<AnonymousEncryption>::new("Order")
+            .with_property("count", "9")
+            .with_property("score", "nan")
+            .with_property("total", "5.0");
+
+        assert!(Selector::parse("total = 5").unwrap().matches(&message));
+        assert!(!Selector::parse("count > 10").unwrap().matches(&message));
+        assert!(Selector::parse("count > '10'").unwrap().matches(&message));
+        assert!(!Selector::parse("score = 5").unwrap().matches(&message));
+        assert!(Selector::parse("score != 5").unwrap().matches(&message));
+    }
+
+    #[test]
+    fn test_invalid_selector() {
+        assert!(Selector::parse("label = ").is_err());
+        assert!(Selector::parse("label = 'unterminated").is_err());
+        assert!(Selector::parse("(priority > 1").is_err());
+    }
+}
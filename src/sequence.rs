@@ -0,0 +1,292 @@
+use crate::message::Message;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Delivery progress of one sender's sequenced messages on a queue.
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(crate) struct SenderSequence<E> {
+    /// Number of the next message that may be delivered.
+    next: u64,
+    /// Messages that arrived ahead of a gap, by sequence number.
+    held: BTreeMap<u64, Message<E>>,
+    /// When the sender was last heard from, in milliseconds since the epoch.
+    #[serde(default)]
+    last_heard: u64,
+    /// When the oldest gap still open appeared, in milliseconds since the epoch.
+    #[serde(default)]
+    gap_since: Option<u64>,
+    /// Last message the sender announced having sent, such as in a multicast heartbeat.
+    #[serde(default)]
+    announced: u64,
+}
+
+impl<E> Default for SenderSequence<E> {
+    fn default() -> Self {
+        Self::starting_at(1)
+    }
+}
+
+impl<E> SenderSequence<E> {
+    /// Progress of a sender first heard from at message `next`, such as a multicast sender
+    /// that was already running when we joined its group.
+    pub(crate) fn starting_at(next: u64) -> Self {
+        Self {
+            next,
+            held: BTreeMap::new(),
+            last_heard: millis(SystemTime::now()),
+            gap_since: None,
+            announced: 0,
+        }
+    }
+
+    /// Number following every message seen from the sender, delivered or held.
+    fn after_last(&self) -> u64 {
+        self.held
+            .keys()
+            .next_back()
+            .map_or(self.next, |last| last + 1)
+    }
+
+    /// Numbers of the messages missing between the next deliverable one and the last held
+    /// or announced.
+    pub(crate) fn missing(&self) -> Vec<u64> {
+        let last = self
+            .held
+            .keys()
+            .next_back()
+            .map_or(self.announced, |&held| held.max(self.announced));
+        (self.next..=last)
+            .filter(|number| !self.held.contains_key(number))
+            .collect()
+    }
+
+    /// Notes that the sender has sent every message up to `last`, opening a gap if some of
+    /// them never arrived.
+    pub(crate) fn announce(&mut self, last: u64) {
+        let now = millis(SystemTime::now());
+        self.last_heard = now;
+        self.announced = self.announced.max(last);
+        if self.announced >= self.next {
+            self.gap_since.get_or_insert(now);
+        }
+    }
+}
+
+impl<E> Clone for SenderSequence<E> {
+    fn clone(&self) -> Self {
+        Self {
+            next: self.next,
+            held: self.held.clone(),
+            last_heard: self.last_heard,
+            gap_since: self.gap_since,
+            announced: self.announced,
+        }
+    }
+}
+
+/// Delivery progress per sender id.
+pub(crate) type Sequences<E> = HashMap<String, SenderSequence<E>>;
+
+/// Delivers `message` to `queue` in its sender's order. Messages already delivered are
+/// dropped as duplicates, and messages ahead of a gap are held until it fills. A message
+/// from a sender but without a sequence number is numbered after everything seen from the
+/// sender; messages without a sender are delivered right away.
+pub(crate) fn deliver<E>(
+    message: Message<E>,
+    queue: &mut VecDeque<Message<E>>,
+    sequences: &mut Sequences<E>,
+) {
+    let Some(sender) = message.sender().map(str::to_string) else {
+        queue.push_back(message);
+        return;
+    };
+    let (message, number) = match message.sequence() {
+        Some((_, number)) => (message, number),
+        None => {
+            let number = sequences.entry(sender.clone()).or_default().after_last();
+            (message.with_sequence(&sender, number), number)
+        }
+    };
+    deliver_numbered(&sender, number, message, queue, sequences);
+}
+
+/// Like [`deliver`], with the sender and sequence number given apart from the message.
+pub(crate) fn deliver_numbered<E>(
+    sender: &str,
+    number: u64,
+    message: Message<E>,
+    queue: &mut VecDeque<Message<E>>,
+    sequences: &mut Sequences<E>,
+) {
+    let sequence = sequences.entry(sender.to_string()).or_default();
+    let now = millis(SystemTime::now());
+    sequence.last_heard = now;
+    if number < sequence.next || sequence.held.contains_key(&number) {
+        tracing::info!("Dropping duplicate message {} from {}", number, sender);
+        return;
+    }
+    sequence.held.insert(number, message);
+    while let Some(message) = sequence.held.remove(&sequence.next) {
+        queue.push_back(message);
+        sequence.next += 1;
+    }
+    if sequence.held.is_empty() && sequence.announced < sequence.next {
+        sequence.gap_since = None;
+    } else {
+        sequence.gap_since.get_or_insert(now);
+    }
+}
+
+/// Gives up on gaps that stayed open for `timeout`, delivering the messages held behind
+/// them, and forgets senders with nothing held that weren't heard from for `timeout`.
+/// Messages filling a gap given up on arrive too late and are dropped as duplicates.
+/// Returns whether anything changed.
+pub(crate) fn expire<E>(
+    queue: &mut VecDeque<Message<E>>,
+    sequences: &mut Sequences<E>,
+    timeout: Duration,
+    now: SystemTime,
+) -> bool {
+    let cutoff = millis(now).saturating_sub(timeout.as_millis() as u64);
+    let senders = sequences.len();
+    let mut released = false;
+    for (sender, sequence) in sequences.iter_mut() {
+        if sequence.gap_since.is_none_or(|since| since > cutoff) {
+            continue;
+        }
+        tracing::warn!(
+            "Giving up on messages {:?} from {} after waiting {:?}",
+            sequence.missing(),
+            sender,
+            timeout
+        );
+        for (number, message) in std::mem::take(&mut sequence.held) {
+            queue.push_back(message);
+            sequence.next = number + 1;
+        }
+        sequence.next = sequence.next.max(sequence.announced + 1);
+        sequence.gap_since = None;
+        // kept for another timeout, to drop the missing messages if they still turn up
+        sequence.last_heard = millis(now);
+        released = true;
+    }
+    sequences.retain(|_, sequence| !sequence.held.is_empty() || sequence.last_heard > cutoff);
+    released || sequences.len() != senders
+}
+
+fn millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::features::AnonymousEncryption;
+
+    #[test]
+    fn test_out_of_order_and_duplicate_messages() {
+        let mut queue = VecDeque::new();
+        let mut sequences = Sequences::<AnonymousEncryption>::new();
+        for (sender, number) in [("a", 2), ("b", 1), ("a", 1), ("a", 2), ("a", 4), ("a", 3)] {
+            let message = Message::new(&format!("{}{}", sender, number));
+            deliver(
+                message.with_sequence(sender, number),
+                &mut queue,
+                &mut sequences,
+            );
+        }
+
+        let delivered: Vec<_> = queue
+            .iter()
+            .map(|message| message.content().as_str())
+            .collect();
+        assert_eq!(delivered, ["b1", "a1", "a2", "a3", "a4"]);
+        assert!(sequences["a"].held.is_empty());
+    }
+
+    #[test]
+    fn test_unnumbered_messages_are_numbered_after_the_last_seen() {
+        let mut queue = VecDeque::new();
+        let mut sequences = Sequences::<AnonymousEncryption>::new();
+        deliver(
+            Message::new("a2").with_sequence("a", 2),
+            &mut queue,
+            &mut sequences,
+        );
+        deliver(
+            Message::new("a3").with_sender("a"),
+            &mut queue,
+            &mut sequences,
+        );
+        assert!(queue.is_empty());
+        deliver(
+            Message::new("a1").with_sequence("a", 1),
+            &mut queue,
+            &mut sequences,
+        );
+
+        let delivered: Vec<_> = queue
+            .iter()
+            .map(|message| (message.content().as_str(), message.sequence().unwrap().1))
+            .collect();
+        assert_eq!(delivered, [("a1", 1), ("a2", 2), ("a3", 3)]);
+    }
+
+    #[test]
+    fn test_gap_open_past_timeout_releases_held_messages() {
+        let mut queue = VecDeque::new();
+        let mut sequences = Sequences::<AnonymousEncryption>::new();
+        for (sender, number) in [("a", 3), ("a", 2), ("b", 1)] {
+            let message = Message::new(&format!("{}{}", sender, number));
+            deliver(
+                message.with_sequence(sender, number),
+                &mut queue,
+                &mut sequences,
+            );
+        }
+        let timeout = Duration::from_secs(60);
+        assert!(!expire(
+            &mut queue,
+            &mut sequences,
+            timeout,
+            SystemTime::now()
+        ));
+        assert_eq!(queue.len(), 1);
+
+        let later = SystemTime::now() + timeout;
+        assert!(expire(&mut queue, &mut sequences, timeout, later));
+        let delivered: Vec<_> = queue
+            .iter()
+            .map(|message| message.content().as_str())
+            .collect();
+        assert_eq!(delivered, ["b1", "a2", "a3"]);
+        // b went quiet, while a is kept around to drop its missing message if it turns up
+        assert!(!sequences.contains_key("b"));
+        assert_eq!(sequences["a"].next, 4);
+        assert!(expire(&mut queue, &mut sequences, timeout, later + timeout));
+        assert!(sequences.is_empty());
+    }
+
+    #[test]
+    fn test_announced_messages_never_sent_are_given_up_on() {
+        let mut queue = VecDeque::new();
+        let mut sequences = Sequences::<AnonymousEncryption>::new();
+        deliver(
+            Message::new("a1").with_sequence("a", 1),
+            &mut queue,
+            &mut sequences,
+        );
+        sequences.get_mut("a").unwrap().announce(3);
+        assert_eq!(sequences["a"].missing(), [2, 3]);
+
+        let timeout = Duration::from_secs(60);
+        let later = SystemTime::now() + timeout;
+        assert!(expire(&mut queue, &mut sequences, timeout, later));
+        assert!(sequences["a"].missing().is_empty());
+        assert_eq!(sequences["a"].next, 4);
+    }
+}
@@ -0,0 +1,162 @@
+use crate::error::MSMQError;
+use crate::features::{DeadLetterFeature, EncryptFeature, JournalFeature};
+use crate::message::Message;
+use crate::queue::Queue;
+use crate::Result;
+use std::collections::HashSet;
+use std::time::SystemTime;
+
+/// Exclusive lock on one message group of a queue. Grouped messages are only handed out
+/// through the session of their group, in the order they were sent. The lock is released
+/// when the session is dropped.
+pub struct Session<J, T, E, D>
+where
+    E: EncryptFeature,
+    D: DeadLetterFeature,
+{
+    queue: Queue<J, T, E, D>,
+    group_id: String,
+}
+
+impl<J, T, E, D> Queue<J, T, E, D>
+where
+    J: JournalFeature + Clone,
+    T: Clone,
+    E: EncryptFeature + Clone,
+    D: DeadLetterFeature + Clone,
+{
+    /// Locks the session for `group_id`, failing if another consumer holds it.
+    pub fn accept_session(&self, group_id: &str) -> Result<Session<J, T, E, D>> {
+        let mut sessions = self
+            .sessions
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?;
+        if !sessions.insert(group_id.to_string()) {
+            return Err(MSMQError::SessionLocked(group_id.to_string()));
+        }
+
+        Ok(Session {
+            queue: self.clone(),
+            group_id: group_id.to_string(),
+        })
+    }
+
+    /// Locks the session of the oldest group whose head is deliverable and that isn't
+    /// locked yet.
+    pub fn accept_next_session(&self) -> Option<Session<J, T, E, D>> {
+        let now = SystemTime::now();
+        let mut sessions = self.sessions.lock().expect("Failed to lock sessions");
+        let queue = self.queue.lock().expect("Failed to lock the queue");
+        let mut seen = HashSet::new();
+        let group_id = queue
+            .iter()
+            .filter_map(|message| Some((message.group_id()?, message)))
+            // only the first message of each group is its head
+            .filter(|(group_id, _)| seen.insert(*group_id))
+            .find(|(group_id, head)| head.is_due(now) && !sessions.contains(*group_id))?
+            .0
+            .to_string();
+        drop(queue);
+        sessions.insert(group_id.clone());
+
+        Some(Session {
+            queue: self.clone(),
+            group_id,
+        })
+    }
+}
+
+impl<J, T, E, D> Session<J, T, E, D>
+where
+    J: JournalFeature,
+    E: EncryptFeature,
+    D: DeadLetterFeature,
+{
+    pub fn group_id(&self) -> &str {
+        &self.group_id
+    }
+
+    /// Receives the next message of the session's group. Nothing is received while the
+    /// group's oldest message isn't due yet.
+    pub fn receive(&mut self) -> Option<Message<E>> {
+        let group_id = self.group_id.clone();
+        self.queue.receive_in_session(
+            |message| message.group_id() == Some(group_id.as_str()),
+            Some(&group_id),
+        )
+    }
+}
+
+impl<J, T, E, D> Drop for Session<J, T, E, D>
+where
+    E: EncryptFeature,
+    D: DeadLetterFeature,
+{
+    fn drop(&mut self) {
+        if let Ok(mut sessions) = self.queue.sessions.lock() {
+            sessions.remove(&self.group_id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::MSMQError;
+    use crate::message::Message;
+    use crate::queue::QueueOps;
+    use crate::queue_builder::QueueBuilder;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_session_receives_group_in_order() {
+        let mut queue = QueueBuilder::new("test_queue").build();
+        for (content, group) in [("A1", "a"), ("B1", "b"), ("A2", "a"), ("B2", "b")] {
+            queue
+                .send(Message::new(content).with_group_id(group))
+                .unwrap();
+        }
+        queue.send(Message::new("Ungrouped")).unwrap();
+
+        let mut session_a = queue.accept_session("a").unwrap();
+        assert!(matches!(
+            queue.accept_session("a"),
+            Err(MSMQError::SessionLocked(group)) if group == "a"
+        ));
+
+        // consumers outside a session skip every group
+        assert_eq!(queue.receive().unwrap().content(), "Ungrouped");
+        assert!(queue.receive().is_none());
+
+        assert_eq!(session_a.receive().unwrap().content(), "A1");
+        assert_eq!(session_a.receive().unwrap().content(), "A2");
+        assert!(session_a.receive().is_none());
+        drop(session_a);
+
+        let mut next = queue.accept_next_session().unwrap();
+        assert_eq!(next.group_id(), "b");
+        assert_eq!(next.receive().unwrap().content(), "B1");
+        assert_eq!(next.receive().unwrap().content(), "B2");
+        assert!(queue.accept_next_session().is_none());
+    }
+
+    #[test]
+    fn test_delayed_head_holds_back_its_group() {
+        let mut queue = QueueBuilder::new("test_queue").build();
+        queue
+            .send(
+                Message::new("A1")
+                    .with_group_id("a")
+                    .with_delay(Duration::from_millis(100)),
+            )
+            .unwrap();
+        queue.send(Message::new("A2").with_group_id("a")).unwrap();
+
+        let mut session = queue.accept_session("a").unwrap();
+        assert!(session.receive().is_none());
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(session.receive().unwrap().content(), "A1");
+        assert_eq!(session.receive().unwrap().content(), "A2");
+    }
+}
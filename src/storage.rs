@@ -0,0 +1,515 @@
+use crate::features::PendingWork;
+use crate::message::Message;
+use crate::sequence::Sequences;
+use crate::Result;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, BufWriter, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Number of journal records after which the journal is folded into a fresh snapshot.
+const COMPACT_AFTER: usize = 1000;
+
+/// Everything a persistent queue writes to disk.
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(crate) struct QueueSnapshot<E> {
+    #[serde(default)]
+    pub messages: Vec<Message<E>>,
+    /// Dedup keys seen within the queue's deduplication window.
+    #[serde(default)]
+    pub dedup: HashMap<String, u64>,
+    #[serde(default)]
+    pub subqueues: HashMap<String, Vec<Message<E>>>,
+    /// Work of transactions that were prepared but not yet committed or rolled back.
+    #[serde(default)]
+    pub transactions: HashMap<String, PendingWork<E>>,
+    /// Delivery progress of senders that number their messages.
+    #[serde(default)]
+    pub sequences: Sequences<E>,
+    /// Messages moved to the queue's dead-letter queue.
+    #[serde(default)]
+    pub dead_letters: Vec<Message<E>>,
+    /// Bumped by every compaction. A journal is only replayed on the snapshot of the same
+    /// generation, so a crash between writing the snapshot and removing the journal can't
+    /// apply the journal twice.
+    #[serde(default)]
+    pub generation: u64,
+}
+
+impl<E> Default for QueueSnapshot<E> {
+    fn default() -> Self {
+        Self {
+            messages: Vec::new(),
+            dedup: HashMap::new(),
+            subqueues: HashMap::new(),
+            transactions: HashMap::new(),
+            sequences: HashMap::new(),
+            dead_letters: Vec::new(),
+            generation: 0,
+        }
+    }
+}
+
+/// One change to a persistent queue, appended to its journal. Replaying the records on top
+/// of the last snapshot gives the current state of the queue.
+#[derive(Serialize, Deserialize)]
+#[serde(bound = "")]
+pub(crate) enum JournalRecord<E> {
+    /// Messages added to the end of the queue.
+    Enqueued(Vec<Message<E>>),
+    /// A message put back at the head of the queue after a failed delivery.
+    Requeued(Message<E>),
+    /// Messages removed for good, from the queue or any of its subqueues.
+    Removed(Vec<String>),
+    DeadLettered(Message<E>),
+    MovedToSubqueue {
+        id: String,
+        subqueue: String,
+    },
+    Deduplicated {
+        key: String,
+        seen_at: u64,
+    },
+    /// A transaction's work, persisted so it can still be committed after a restart.
+    Prepared {
+        txn_id: String,
+        work: PendingWork<E>,
+    },
+    Committed {
+        txn_id: String,
+        /// Ids of the messages the transaction received.
+        received: Vec<String>,
+        /// Messages its sends made available, in order.
+        delivered: Vec<Message<E>>,
+        /// Progress of the senders that numbered their messages.
+        sequences: Sequences<E>,
+    },
+    RolledBack {
+        txn_id: String,
+        /// Received messages as they were put back, with the position they were taken from.
+        restored: Vec<(usize, Message<E>)>,
+    },
+}
+
+impl<E> QueueSnapshot<E> {
+    fn remove_message(&mut self, id: &str) -> Option<Message<E>> {
+        let position = self
+            .messages
+            .iter()
+            .position(|message| message.id() == id)?;
+        Some(self.messages.remove(position))
+    }
+
+    fn apply(&mut self, record: JournalRecord<E>) {
+        match record {
+            JournalRecord::Enqueued(messages) => self.messages.extend(messages),
+            JournalRecord::Requeued(message) => {
+                self.remove_message(message.id());
+                self.messages.insert(0, message);
+            }
+            JournalRecord::Removed(ids) => {
+                for id in ids {
+                    if self.remove_message(&id).is_none() {
+                        for messages in self.subqueues.values_mut() {
+                            messages.retain(|message| message.id() != id);
+                        }
+                    }
+                }
+            }
+            JournalRecord::DeadLettered(message) => {
+                self.remove_message(message.id());
+                // the record can land after a snapshot that already has the message
+                if !self
+                    .dead_letters
+                    .iter()
+                    .any(|dead| dead.id() == message.id())
+                {
+                    self.dead_letters.push(message);
+                }
+            }
+            JournalRecord::MovedToSubqueue { id, subqueue } => {
+                if let Some(message) = self.remove_message(&id) {
+                    self.subqueues.entry(subqueue).or_default().push(message);
+                }
+            }
+            JournalRecord::Deduplicated { key, seen_at } => {
+                self.dedup.insert(key, seen_at);
+            }
+            JournalRecord::Prepared { txn_id, work } => {
+                for (_, message) in &work.receives {
+                    self.remove_message(message.id());
+                }
+                self.transactions.insert(txn_id, work);
+            }
+            JournalRecord::Committed {
+                txn_id,
+                received,
+                delivered,
+                sequences,
+            } => {
+                self.transactions.remove(&txn_id);
+                for id in received {
+                    self.remove_message(&id);
+                }
+                self.messages.extend(delivered);
+                self.sequences.extend(sequences);
+            }
+            JournalRecord::RolledBack { txn_id, restored } => {
+                self.transactions.remove(&txn_id);
+                // messages received outside a prepared transaction never left the snapshot
+                for (position, message) in restored.into_iter().rev() {
+                    match self
+                        .messages
+                        .iter_mut()
+                        .find(|queued| queued.id() == message.id())
+                    {
+                        Some(queued) => *queued = message,
+                        None => {
+                            let position = position.min(self.messages.len());
+                            self.messages.insert(position, message);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Files backing a persistent queue: a snapshot, and a journal of the changes made since.
+/// Changes are buffered in the order they happen and appended together, with one fsync per
+/// flush; once the journal grows long, it is folded into a new snapshot.
+pub(crate) struct QueueStorage<E> {
+    path: PathBuf,
+    journal_path: PathBuf,
+    /// Records not yet written, in the order their changes were made.
+    buffered: Mutex<Vec<JournalRecord<E>>>,
+    journal: Mutex<Journal>,
+}
+
+/// The open journal file and the number of records in it.
+#[derive(Default)]
+struct Journal {
+    file: Option<File>,
+    records: usize,
+    /// Generation of the snapshot the journal applies to, written as its first line.
+    generation: u64,
+    /// An append failed, so the journal may be torn and lacks records other threads count
+    /// on; only a snapshot brings the files back in line with memory.
+    needs_snapshot: bool,
+}
+
+impl<E> QueueStorage<E> {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        let path = path.as_ref().to_path_buf();
+        let mut journal_path = OsString::from(path.as_os_str());
+        journal_path.push(".journal");
+        Self {
+            path,
+            journal_path: journal_path.into(),
+            buffered: Mutex::new(Vec::new()),
+            journal: Mutex::new(Journal::default()),
+        }
+    }
+
+    /// Whether a snapshot has been written yet.
+    pub fn exists(&self) -> bool {
+        self.path.exists()
+    }
+
+    /// Reads the snapshot and replays the journal on top of it.
+    pub fn load(&self) -> Result<QueueSnapshot<E>> {
+        let mut snapshot: QueueSnapshot<E> = read_json(&self.path)?;
+        let mut journal = self.journal.lock().expect("Failed to lock storage");
+        journal.generation = snapshot.generation;
+        let file = match File::open(&self.journal_path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(snapshot),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut reader = BufReader::new(file);
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        if serde_json::from_str::<u64>(&line).ok() != Some(snapshot.generation) {
+            tracing::info!(
+                "Discarding journal {:?} of an older snapshot",
+                self.journal_path
+            );
+            fs::remove_file(&self.journal_path)?;
+            return Ok(snapshot);
+        }
+
+        let mut valid_len = line.len() as u64;
+        loop {
+            line.clear();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            match serde_json::from_str(&line) {
+                Ok(record) if line.ends_with('\n') => snapshot.apply(record),
+                // a crash can leave the last record half written; cut it off so new records
+                // aren't appended behind it
+                _ => {
+                    tracing::warn!("Truncating torn record in journal {:?}", self.journal_path);
+                    OpenOptions::new()
+                        .write(true)
+                        .open(&self.journal_path)?
+                        .set_len(valid_len)?;
+                    break;
+                }
+            }
+            valid_len += line.len() as u64;
+            journal.records += 1;
+        }
+        Ok(snapshot)
+    }
+
+    /// Buffers `record` until the next [`flush`](Self::flush). Call it while holding the lock
+    /// that guards the change, so records are buffered in the order the changes happened.
+    pub fn record(&self, record: JournalRecord<E>) {
+        self.buffered
+            .lock()
+            .expect("Failed to lock storage")
+            .push(record);
+    }
+
+    /// Drops the buffered records. Called by `take_snapshot` in [`compact`](Self::compact)
+    /// while it holds every lock of the queue, as the snapshot already has their changes.
+    pub fn discard_buffered(&self) {
+        self.buffered
+            .lock()
+            .expect("Failed to lock storage")
+            .clear();
+    }
+
+    /// Appends the buffered records to the journal and fsyncs it, compacting the journal
+    /// with `take_snapshot` once it has grown past [`COMPACT_AFTER`] records.
+    pub fn flush<F>(&self, take_snapshot: F) -> Result<()>
+    where
+        F: FnOnce() -> QueueSnapshot<E>,
+    {
+        let mut journal = self.journal.lock().expect("Failed to lock storage");
+        if journal.records >= COMPACT_AFTER || journal.needs_snapshot {
+            return self.write_snapshot(&mut journal, take_snapshot());
+        }
+
+        let records = std::mem::take(&mut *self.buffered.lock().expect("Failed to lock storage"));
+        if records.is_empty() {
+            return Ok(());
+        }
+        if let Err(e) = self.append(&mut journal, &records) {
+            tracing::warn!(
+                "Failed to append to journal {:?}, writing a snapshot instead: {}",
+                self.journal_path,
+                e
+            );
+            journal.file = None;
+            journal.needs_snapshot = true;
+            return self.write_snapshot(&mut journal, take_snapshot());
+        }
+        Ok(())
+    }
+
+    fn append(&self, journal: &mut Journal, records: &[JournalRecord<E>]) -> Result<()> {
+        let generation = journal.generation;
+        let mut writer = match &mut journal.file {
+            Some(file) => BufWriter::new(file),
+            file => {
+                let file = file.insert(
+                    OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&self.journal_path)?,
+                );
+                let mut writer = BufWriter::new(file);
+                if writer.get_ref().metadata()?.len() == 0 {
+                    serde_json::to_writer(&mut writer, &generation)?;
+                    writer.write_all(b"\n")?;
+                }
+                writer
+            }
+        };
+        for record in records {
+            serde_json::to_writer(&mut writer, record)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()?;
+        writer.get_ref().sync_data()?;
+        drop(writer);
+        journal.records += records.len();
+        Ok(())
+    }
+
+    /// Writes the snapshot produced by `take_snapshot` and empties the journal. The snapshot
+    /// is taken while holding the journal, so concurrent writes can't overwrite newer state
+    /// with older state.
+    pub fn compact<F>(&self, take_snapshot: F) -> Result<()>
+    where
+        F: FnOnce() -> QueueSnapshot<E>,
+    {
+        let mut journal = self.journal.lock().expect("Failed to lock storage");
+        self.write_snapshot(&mut journal, take_snapshot())
+    }
+
+    fn write_snapshot(&self, journal: &mut Journal, mut snapshot: QueueSnapshot<E>) -> Result<()> {
+        snapshot.generation = journal.generation + 1;
+        write_json(&self.path, &snapshot)?;
+        journal.generation = snapshot.generation;
+        journal.file = None;
+        journal.records = 0;
+        journal.needs_snapshot = false;
+        match fs::remove_file(&self.journal_path) {
+            Err(e) if e.kind() != ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Reads a JSON file, falling back to the default value if it doesn't exist yet.
+pub(crate) fn read_json<T: DeserializeOwned + Default>(path: &Path) -> Result<T> {
+    match File::open(path) {
+        Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(T::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Writes `value` to a temporary file, fsyncs it and renames it over `path`, so a crash
+/// leaves either the old or the new contents but never a torn file.
+pub(crate) fn write_json<T: Serialize>(path: &Path, value: &T) -> Result<()> {
+    let mut tmp_path = OsString::from(path.as_os_str());
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let file = File::create(&tmp_path)?;
+    let mut writer = BufWriter::new(file);
+    serde_json::to_writer(&mut writer, value)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::features::AnonymousEncryption;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = QueueStorage::<AnonymousEncryption>::new(dir.path().join("queue.msmq"));
+        assert!(storage.load().unwrap().messages.is_empty());
+
+        storage
+            .compact(|| QueueSnapshot {
+                messages: vec![Message::new("Stored message").with_label("label")],
+                ..Default::default()
+            })
+            .unwrap();
+
+        let loaded = storage.load().unwrap();
+        assert_eq!(loaded.messages.len(), 1);
+        assert_eq!(loaded.messages[0].content(), "Stored message");
+        assert_eq!(loaded.messages[0].label(), "label");
+    }
+
+    #[test]
+    fn test_journal_replays_on_top_of_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queue.msmq");
+        let storage = QueueStorage::<AnonymousEncryption>::new(&path);
+        let first = Message::new("First");
+        let second = Message::new("Second");
+        storage
+            .compact(|| QueueSnapshot {
+                messages: vec![first.clone()],
+                ..Default::default()
+            })
+            .unwrap();
+
+        storage.record(JournalRecord::Enqueued(vec![second.clone()]));
+        storage.record(JournalRecord::Removed(vec![first.id().to_string()]));
+        storage.record(JournalRecord::DeadLettered(second.clone()));
+        storage.flush(QueueSnapshot::default).unwrap();
+
+        let loaded = QueueStorage::<AnonymousEncryption>::new(&path)
+            .load()
+            .unwrap();
+        assert!(loaded.messages.is_empty());
+        assert_eq!(loaded.dead_letters.len(), 1);
+        assert_eq!(loaded.dead_letters[0].content(), "Second");
+    }
+
+    #[test]
+    fn test_failed_append_is_saved_in_a_snapshot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queue.msmq");
+        let storage = QueueStorage::<AnonymousEncryption>::new(&path);
+        // a directory in place of the journal makes appending to it fail
+        fs::create_dir(&storage.journal_path).unwrap();
+
+        let message = Message::new("Unjournaled");
+        storage.record(JournalRecord::Enqueued(vec![message.clone()]));
+        let _ = storage.flush(|| QueueSnapshot {
+            messages: vec![message.clone()],
+            ..Default::default()
+        });
+        fs::remove_dir(&storage.journal_path).unwrap();
+
+        let loaded = QueueStorage::<AnonymousEncryption>::new(&path)
+            .load()
+            .unwrap();
+        assert_eq!(loaded.messages.len(), 1);
+        assert_eq!(loaded.messages[0].id(), message.id());
+    }
+
+    #[test]
+    fn test_sibling_files_are_saved_apart() {
+        let dir = tempfile::tempdir().unwrap();
+        let writers: Vec<_> = ["orders.msmq", "orders.txnlog"]
+            .into_iter()
+            .map(|name| {
+                let path = dir.path().join(name);
+                std::thread::spawn(move || {
+                    for _ in 0..200 {
+                        write_json(&path, &vec![name.to_string()]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        for name in ["orders.msmq", "orders.txnlog"] {
+            let saved: Vec<String> = read_json(&dir.path().join(name)).unwrap();
+            assert_eq!(saved, [name]);
+        }
+    }
+
+    #[test]
+    fn test_long_journal_is_compacted() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("queue.msmq");
+        let storage = QueueStorage::<AnonymousEncryption>::new(&path);
+        for _ in 0..COMPACT_AFTER {
+            storage.record(JournalRecord::Enqueued(vec![Message::new("Message")]));
+        }
+        storage.flush(QueueSnapshot::default).unwrap();
+        assert!(storage.journal_path.exists());
+
+        storage
+            .flush(|| QueueSnapshot {
+                messages: vec![Message::new("Compacted")],
+                ..Default::default()
+            })
+            .unwrap();
+        assert!(!storage.journal_path.exists());
+        assert_eq!(storage.load().unwrap().messages.len(), 1);
+    }
+}
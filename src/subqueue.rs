@@ -0,0 +1,146 @@
+use crate::error::MSMQError;
+use crate::features::{DeadLetterFeature, EncryptFeature, JournalFeature};
+use crate::message::Message;
+use crate::queue::Queue;
+use crate::storage::JournalRecord;
+use crate::Result;
+
+/// Subqueue conventionally used for messages that can't be processed.
+pub const POISON_SUBQUEUE: &str = "poison";
+
+/// Splits a format name like `orders;poison` into the queue and subqueue name.
+pub fn parse_format_name(format_name: &str) -> (&str, Option<&str>) {
+    match format_name.split_once(';') {
+        Some((queue, subqueue)) => (queue, Some(subqueue)),
+        None => (format_name, None),
+    }
+}
+
+impl<J, T, E, D> Queue<J, T, E, D>
+where
+    J: JournalFeature,
+    E: EncryptFeature,
+    D: DeadLetterFeature,
+{
+    /// Moves the message with `id` from the queue into the subqueue `name`, creating the
+    /// subqueue if needed. The message stays part of this queue.
+    pub fn move_to_subqueue(&mut self, id: &str, name: &str) -> Result<()> {
+        {
+            // both stay locked until the move is recorded, so a compaction sees the message
+            // in one place or the other
+            let mut queue = self
+                .queue
+                .lock()
+                .map_err(|e| MSMQError::Custom(e.to_string()))?;
+            let mut subqueues = self
+                .subqueues
+                .lock()
+                .map_err(|e| MSMQError::Custom(e.to_string()))?;
+            let position = queue
+                .iter()
+                .position(|message| message.id() == id)
+                .ok_or_else(|| MSMQError::MessageNotFound(id.to_string()))?;
+            self.record(|| JournalRecord::MovedToSubqueue {
+                id: id.to_string(),
+                subqueue: name.to_string(),
+            });
+            subqueues
+                .entry(name.to_string())
+                .or_default()
+                .extend(queue.remove(position));
+        }
+
+        self.persist()
+    }
+
+    pub fn receive_from_subqueue(&mut self, name: &str) -> Option<Message<E>> {
+        let result = {
+            let mut subqueues = self.subqueues.lock().expect("Failed to lock subqueues");
+            let message = subqueues.get_mut(name)?.pop_front()?;
+            self.record(|| JournalRecord::Removed(vec![message.id().to_string()]));
+            Some(message)
+        };
+
+        if let Some(ref message) = result {
+            self.journaled_queue
+                .append_journal_messages(message.content());
+            self.persist_or_log();
+        }
+
+        result
+    }
+
+    pub fn peek_subqueue(&self, name: &str) -> Option<Message<E>> {
+        self.subqueues
+            .lock()
+            .expect("Failed to lock subqueues")
+            .get(name)?
+            .front()
+            .cloned()
+    }
+
+    pub fn subqueue_count(&self, name: &str) -> usize {
+        self.subqueues
+            .lock()
+            .expect("Failed to lock subqueues")
+            .get(name)
+            .map_or(0, |messages| messages.len())
+    }
+
+    pub fn subqueue_names(&self) -> Vec<String> {
+        self.subqueues
+            .lock()
+            .expect("Failed to lock subqueues")
+            .keys()
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::QueueOps;
+    use crate::queue_builder::QueueBuilder;
+
+    #[test]
+    fn test_move_to_poison_subqueue() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("orders.msmq");
+        let mut queue = QueueBuilder::new("orders").with_persistence(&path).build();
+
+        let message = Message::new("Unparseable order");
+        let id = message.id().to_string();
+        queue.send(message).unwrap();
+        queue.send(Message::new("Order")).unwrap();
+
+        queue.move_to_subqueue(&id, POISON_SUBQUEUE).unwrap();
+        assert!(matches!(
+            queue.move_to_subqueue(&id, POISON_SUBQUEUE),
+            Err(MSMQError::MessageNotFound(_))
+        ));
+        assert_eq!(queue.message_count().unwrap(), 1);
+        assert_eq!(queue.subqueue_count(POISON_SUBQUEUE), 1);
+
+        let mut queue = QueueBuilder::new("orders").with_persistence(&path).build();
+        assert_eq!(queue.peek_subqueue(POISON_SUBQUEUE).unwrap().id(), id);
+        assert_eq!(
+            queue
+                .receive_from_subqueue(POISON_SUBQUEUE)
+                .unwrap()
+                .content(),
+            "Unparseable order"
+        );
+        assert_eq!(queue.subqueue_count(POISON_SUBQUEUE), 0);
+        assert_eq!(queue.receive().unwrap().content(), "Order");
+    }
+
+    #[test]
+    fn test_parse_format_name() {
+        assert_eq!(
+            parse_format_name("orders;poison"),
+            ("orders", Some("poison"))
+        );
+        assert_eq!(parse_format_name("orders"), ("orders", None));
+    }
+}
@@ -0,0 +1,240 @@
+use crate::error::MSMQError;
+use crate::message::Message;
+use crate::multicast_group::{deliver_all, GroupMember};
+use crate::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Word {
+    Literal(String),
+    /// `*`, exactly one word.
+    One,
+    /// `#`, zero or more words.
+    Any,
+}
+
+/// A subscription pattern over dot-separated topic names, e.g. `orders.*.created` or
+/// `orders.#`. `*` stands for exactly one word and `#` for any number of words, including
+/// none.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TopicPattern {
+    pattern: String,
+    words: Vec<Word>,
+}
+
+impl TopicPattern {
+    pub fn parse(pattern: &str) -> Result<Self> {
+        let words = pattern
+            .split('.')
+            .map(|word| match word {
+                "*" => Ok(Word::One),
+                "#" => Ok(Word::Any),
+                "" => Err(MSMQError::InvalidTopic(format!(
+                    "{} has an empty word",
+                    pattern
+                ))),
+                word if word.contains(['*', '#']) => Err(MSMQError::InvalidTopic(format!(
+                    "wildcards must be whole words in {}",
+                    pattern
+                ))),
+                word => Ok(Word::Literal(word.to_string())),
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self {
+            pattern: pattern.to_string(),
+            words,
+        })
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.pattern
+    }
+
+    pub fn matches(&self, topic: &str) -> bool {
+        let topic: Vec<_> = topic.split('.').collect();
+        matches_words(&self.words, &topic)
+    }
+}
+
+fn matches_words(pattern: &[Word], topic: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => topic.is_empty(),
+        Some((Word::Any, rest)) => {
+            (0..=topic.len()).any(|skip| matches_words(rest, &topic[skip..]))
+        }
+        Some((Word::One, rest)) => !topic.is_empty() && matches_words(rest, &topic[1..]),
+        Some((Word::Literal(word), rest)) => {
+            topic.first() == Some(&word.as_str()) && matches_words(rest, &topic[1..])
+        }
+    }
+}
+
+struct Subscriber {
+    member: Arc<dyn GroupMember>,
+    patterns: Vec<TopicPattern>,
+}
+
+/// Routes messages published to a topic to every queue subscribed with a matching pattern.
+#[derive(Clone, Default)]
+pub struct TopicExchange {
+    /// Subscribed queues, keyed by queue name.
+    subscribers: Arc<Mutex<HashMap<String, Subscriber>>>,
+}
+
+impl TopicExchange {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Patterns each queue is subscribed with, by queue name.
+    pub fn subscriptions(&self) -> HashMap<String, Vec<String>> {
+        self.subscribers
+            .lock()
+            .expect("Failed to lock topic subscribers")
+            .iter()
+            .map(|(name, subscriber)| {
+                let patterns = subscriber
+                    .patterns
+                    .iter()
+                    .map(|pattern| pattern.as_str().to_string())
+                    .collect();
+                (name.clone(), patterns)
+            })
+            .collect()
+    }
+
+    /// Sends a copy of `message` to every queue with a pattern matching `topic`, once per
+    /// queue however many of its patterns match.
+    pub fn publish(&self, topic: &str, message: Message) -> Result<()> {
+        if topic.split('.').any(|word| matches!(word, "" | "*" | "#")) {
+            return Err(MSMQError::InvalidTopic(format!(
+                "can't publish to {}",
+                topic
+            )));
+        }
+
+        let members: Vec<_> = self
+            .subscribers
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?
+            .values()
+            .filter(|subscriber| subscriber.patterns.iter().any(|p| p.matches(topic)))
+            .map(|subscriber| Arc::clone(&subscriber.member))
+            .collect();
+
+        deliver_all(topic, members, message)
+    }
+
+    pub(crate) fn subscribe(
+        &self,
+        name: &str,
+        pattern: &str,
+        member: Arc<dyn GroupMember>,
+    ) -> Result<()> {
+        let pattern = TopicPattern::parse(pattern)?;
+        let mut subscribers = self
+            .subscribers
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?;
+        let subscriber = subscribers
+            .entry(name.to_string())
+            .or_insert_with(|| Subscriber {
+                member: Arc::clone(&member),
+                patterns: Vec::new(),
+            });
+        if subscriber.member.member_id() != member.member_id() {
+            return Err(MSMQError::NameInUse(name.to_string()));
+        }
+        if !subscriber.patterns.contains(&pattern) {
+            subscriber.patterns.push(pattern);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn unsubscribe(&self, name: &str, pattern: &str) -> Result<()> {
+        let mut subscribers = self
+            .subscribers
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?;
+        if let Some(subscriber) = subscribers.get_mut(name) {
+            subscriber.patterns.retain(|p| p.as_str() != pattern);
+            if subscriber.patterns.is_empty() {
+                subscribers.remove(name);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::QueueOps;
+    use crate::queue_builder::QueueBuilder;
+
+    #[test]
+    fn test_wildcards() {
+        let created = TopicPattern::parse("orders.*.created").unwrap();
+        assert!(created.matches("orders.eu.created"));
+        assert!(!created.matches("orders.created"));
+        assert!(!created.matches("orders.eu.west.created"));
+
+        let orders = TopicPattern::parse("orders.#").unwrap();
+        assert!(orders.matches("orders"));
+        assert!(orders.matches("orders.eu.west.created"));
+        assert!(!orders.matches("invoices.eu"));
+
+        assert!(TopicPattern::parse("orders.#.created")
+            .unwrap()
+            .matches("orders.created"));
+        assert!(TopicPattern::parse("orders..created").is_err());
+        assert!(TopicPattern::parse("orders.eu*").is_err());
+    }
+
+    #[test]
+    fn test_publish_reaches_matching_subscribers_once() {
+        let exchange = TopicExchange::new();
+        let mut audit = QueueBuilder::new("audit").build();
+        let mut shipping = QueueBuilder::new("shipping").build();
+        audit.subscribe(&exchange, "orders.#").unwrap();
+        audit.subscribe(&exchange, "orders.*.created").unwrap();
+        shipping.subscribe(&exchange, "orders.*.created").unwrap();
+
+        exchange
+            .publish("orders.eu.created", Message::new("Order created"))
+            .unwrap();
+        exchange
+            .publish("orders.eu.cancelled", Message::new("Order cancelled"))
+            .unwrap();
+        assert!(exchange.publish("orders.*", Message::new("Bad")).is_err());
+
+        assert_eq!(audit.message_count().unwrap(), 2);
+        assert_eq!(shipping.message_count().unwrap(), 1);
+        assert_eq!(shipping.receive().unwrap().content(), "Order created");
+
+        audit.unsubscribe(&exchange, "orders.#").unwrap();
+        shipping.unsubscribe(&exchange, "orders.*.created").unwrap();
+        assert_eq!(exchange.subscriptions()["audit"], ["orders.*.created"]);
+        assert!(!exchange.subscriptions().contains_key("shipping"));
+    }
+
+    #[test]
+    fn test_other_queue_with_a_subscribed_name_is_rejected() {
+        let exchange = TopicExchange::new();
+        let mut audit = QueueBuilder::new("audit").build();
+        let mut other_audit = QueueBuilder::new("audit").build();
+        audit.subscribe(&exchange, "orders.#").unwrap();
+        audit.clone().subscribe(&exchange, "invoices.#").unwrap();
+
+        assert!(matches!(
+            other_audit.subscribe(&exchange, "orders.#"),
+            Err(MSMQError::NameInUse(name)) if name == "audit"
+        ));
+        exchange
+            .publish("orders.eu.created", Message::new("Order created"))
+            .unwrap();
+        assert_eq!(audit.message_count().unwrap(), 1);
+        assert_eq!(other_audit.message_count().unwrap(), 0);
+    }
+}
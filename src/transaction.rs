@@ -2,36 +2,862 @@ use crate::error::MSMQError;
 use crate::features::{
     AnonymousEncryption, BasicEncryption, EmptyDeadletterQueue, EmptyJournal, TransactionalQueue,
 };
-use crate::message::Message;
+use crate::message::{generate_id, Message};
 use crate::queue::{Queue, QueueOps};
 use crate::queue_builder::QueueBuilder;
 use crate::security::Security;
+use crate::transaction_log::TransactionLog;
 use crate::Result;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
+use lazy_static::lazy_static;
+use std::cmp::Ordering as CmpOrdering;
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
 
+lazy_static! {
+    static ref REAPER: Arc<Reaper> = Reaper::start();
+}
+
+/// A resource whose changes are buffered under a transaction id until the transaction
+/// commits. Queues implement it, and any other resource implementing it can be enlisted in a
+/// [`DistributedTransaction`](crate::distributed_transaction::DistributedTransaction) to
+/// share its outcome.
+pub trait ResourceManager: Send + Sync {
+    /// Durably records everything buffered under `txn_id`, so it can still be committed or
+    /// rolled back after a crash.
+    fn prepare(&self, txn_id: &str) -> Result<()>;
+    /// Applies everything buffered under `txn_id`.
+    fn commit(&self, txn_id: &str) -> Result<()>;
+    /// Discards everything buffered under `txn_id`.
+    fn rollback(&self, txn_id: &str) -> Result<()>;
+    /// Lists the transactions that were prepared but neither committed nor rolled back,
+    /// typically because the process died in between. Resources that keep nothing durable
+    /// have nothing to recover.
+    fn recover(&self) -> Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+
+    /// Tells resources enlisted under the same name apart: enlisting a resource under a
+    /// name already taken by one with a different id fails. Resources without an id, the
+    /// default, are identified by their name alone.
+    fn resource_id(&self) -> Option<usize> {
+        None
+    }
+
+    /// Log the resource relies on to finish interrupted commits after a crash. Enlisting
+    /// the resource makes the transaction record its outcome there.
+    fn transaction_log(&self) -> Option<Arc<TransactionLog>> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionState {
+    Active,
+    /// Every participant has durably prepared; only commit or abort remain.
+    Prepared,
+    Committed,
+    Aborted,
+}
+
+type Participants = HashMap<String, Arc<dyn ResourceManager>>;
+
+/// Bookkeeping shared by [`Transaction`] and
+/// [`DistributedTransaction`](crate::distributed_transaction::DistributedTransaction): the
+/// enlisted participants and the state of the transaction.
+pub(crate) struct TransactionCore {
+    id: String,
+    state: Mutex<TransactionState>,
+    /// Resources touched by the transaction, keyed by name. Held while the transaction
+    /// changes state, so completing it can't interleave with it timing out.
+    participants: Mutex<Participants>,
+    log: Mutex<Option<Arc<TransactionLog>>>,
+    /// Always prepare every participant before committing, even without a log.
+    two_phase: bool,
+    /// Started by a coordinator elsewhere, which decides the outcome.
+    branch: bool,
+    started: Instant,
+    /// When a still active transaction is aborted.
+    deadline: Mutex<Option<Instant>>,
+    timed_out: AtomicBool,
+}
+
+impl TransactionCore {
+    pub fn new() -> Self {
+        Self {
+            id: generate_id(),
+            state: Mutex::new(TransactionState::Active),
+            participants: Mutex::new(HashMap::new()),
+            log: Mutex::new(None),
+            two_phase: false,
+            branch: false,
+            started: Instant::now(),
+            deadline: Mutex::new(None),
+            timed_out: AtomicBool::new(false),
+        }
+    }
+
+    pub fn two_phase() -> Self {
+        Self {
+            two_phase: true,
+            ..Self::new()
+        }
+    }
+
+    /// Uses `id` instead of a generated one, for a transaction started elsewhere.
+    pub fn with_id(mut self, id: &str) -> Self {
+        self.id = id.to_string();
+        self.branch = true;
+        self
+    }
+
+    pub fn set_log(&self, log: Arc<TransactionLog>) {
+        *self.log.lock().expect("Failed to lock transaction") = Some(log);
+    }
+
+    /// Records the outcome in `log` too, failing if the transaction already uses another.
+    fn adopt_log(&self, log: Arc<TransactionLog>) -> Result<()> {
+        let mut current = self
+            .log
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?;
+        match &*current {
+            Some(current) if !Arc::ptr_eq(current, &log) => Err(MSMQError::Custom(format!(
+                "Transaction {} already records its outcome in another log",
+                self.id
+            ))),
+            Some(_) => Ok(()),
+            None => {
+                *current = Some(log);
+                Ok(())
+            }
+        }
+    }
+
+    fn log(&self) -> Option<Arc<TransactionLog>> {
+        self.log.lock().expect("Failed to lock transaction").clone()
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn state(&self) -> TransactionState {
+        *self.state.lock().expect("Failed to lock transaction")
+    }
+
+    fn set_state(&self, state: TransactionState) {
+        *self.state.lock().expect("Failed to lock transaction") = state;
+    }
+
+    fn lock_participants(&self) -> Result<MutexGuard<'_, Participants>> {
+        self.participants
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))
+    }
+
+    /// Error for an operation that needs the transaction to still be active.
+    fn not_active(&self) -> MSMQError {
+        if self.timed_out.load(Ordering::SeqCst) {
+            MSMQError::TransactionTimedOut(self.id.clone())
+        } else {
+            MSMQError::TransactionNotActive(self.id.clone())
+        }
+    }
+
+    /// Aborts the transaction if it is still active once `timeout` has passed since it
+    /// started. Prepared transactions are left to their coordinator.
+    pub fn set_timeout(self: &Arc<Self>, timeout: Duration) {
+        let deadline = self.started + timeout;
+        *self.deadline.lock().expect("Failed to lock transaction") = Some(deadline);
+        REAPER.schedule(deadline, Arc::downgrade(self));
+    }
+
+    /// Like [`set_timeout`](Self::set_timeout), unless the transaction already has one.
+    pub fn set_default_timeout(self: &Arc<Self>, timeout: Duration) {
+        if self
+            .deadline
+            .lock()
+            .expect("Failed to lock transaction")
+            .is_none()
+        {
+            self.set_timeout(timeout);
+        }
+    }
+
+    /// Aborts the transaction if it is still active past its deadline.
+    fn expire(&self, participants: &Participants) -> Result<()> {
+        let due = self
+            .deadline
+            .lock()
+            .expect("Failed to lock transaction")
+            .is_some_and(|deadline| Instant::now() >= deadline);
+        if due && self.state() == TransactionState::Active {
+            tracing::warn!("Transaction {} timed out, aborting it", self.id);
+            self.timed_out.store(true, Ordering::SeqCst);
+            self.abort_participants(participants)?;
+        }
+        Ok(())
+    }
+
+    /// Adds `resource` to the transaction, failing once it is no longer active or if a
+    /// different resource was enlisted under `name`. A resource with a log of its own has
+    /// the transaction record its outcome there.
+    pub fn enlist(&self, name: &str, resource: Arc<dyn ResourceManager>) -> Result<()> {
+        self.enlist_with(name, resource, || Ok(()))
+    }
+
+    /// Like [`enlist`](Self::enlist), then runs `work` before the transaction can commit or
+    /// abort, so work buffered by `work` is always seen by the outcome.
+    pub fn enlist_with<R>(
+        &self,
+        name: &str,
+        resource: Arc<dyn ResourceManager>,
+        work: impl FnOnce() -> Result<R>,
+    ) -> Result<R> {
+        let mut participants = self.lock_participants()?;
+        self.expire(&participants)?;
+        if self.state() != TransactionState::Active {
+            return Err(self.not_active());
+        }
+
+        match participants.get(name) {
+            Some(enlisted) => {
+                if let (Some(enlisted), Some(id)) = (enlisted.resource_id(), resource.resource_id())
+                {
+                    if enlisted != id {
+                        return Err(MSMQError::NameInUse(name.to_string()));
+                    }
+                }
+            }
+            None => {
+                if let Some(log) = resource.transaction_log() {
+                    self.adopt_log(log)?;
+                }
+                participants.insert(name.to_string(), resource);
+            }
+        }
+        work()
+    }
+
+    /// Phase one: asks every participant to prepare. If any of them fails, the transaction
+    /// is rolled back everywhere.
+    pub fn prepare(&self) -> Result<()> {
+        let participants = self.lock_participants()?;
+        self.expire(&participants)?;
+        self.prepare_participants(&participants)
+    }
+
+    fn prepare_participants(&self, participants: &Participants) -> Result<()> {
+        if self.state() != TransactionState::Active {
+            return Err(self.not_active());
+        }
+
+        let prepared = participants
+            .values()
+            .try_for_each(|resource| resource.prepare(&self.id))
+            .and_then(|_| match self.log() {
+                Some(log) => log.record_prepared(
+                    &self.id,
+                    participants.keys().cloned().collect(),
+                    self.branch,
+                ),
+                None => Ok(()),
+            });
+        if let Err(e) = prepared {
+            self.set_state(TransactionState::Aborted);
+            self.rollback_all(participants)?;
+            return Err(e);
+        }
+
+        self.set_state(TransactionState::Prepared);
+        Ok(())
+    }
+
+    /// Commits the transaction. For two-phase transactions, or ones with a log, this runs
+    /// both phases of two-phase commit, recording the decision before applying it.
+    /// Otherwise every participant is committed directly.
+    pub fn commit(&self) -> Result<()> {
+        let participants = self.lock_participants()?;
+        self.expire(&participants)?;
+        let log = self.log();
+        match self.state() {
+            TransactionState::Active if log.is_none() && !self.two_phase => {
+                return self.commit_one_phase(&participants)
+            }
+            TransactionState::Active => self.prepare_participants(&participants)?,
+            TransactionState::Prepared => {}
+            _ => return Err(self.not_active()),
+        }
+
+        if let Some(log) = &log {
+            if let Err(e) = log.record_commit(&self.id) {
+                self.abort_participants(&participants)?;
+                return Err(e);
+            }
+        }
+        self.set_state(TransactionState::Committed);
+
+        // the outcome is decided now, so keep applying even if a participant fails; with a
+        // log the commit record stays behind until the failed ones recover
+        let mut result = Ok(());
+        let mut committed = Vec::new();
+        for (name, resource) in participants.iter() {
+            match resource.commit(&self.id) {
+                Ok(()) => committed.push(name.clone()),
+                Err(e) => result = Err(e),
+            }
+        }
+
+        if let Some(log) = &log {
+            log.acknowledge(&self.id, &committed)?;
+        }
+        result
+    }
+
+    fn commit_one_phase(&self, participants: &Participants) -> Result<()> {
+        self.set_state(TransactionState::Committed);
+
+        let mut resources = participants.values();
+        while let Some(resource) = resources.next() {
+            if let Err(e) = resource.commit(&self.id) {
+                for resource in resources {
+                    resource.rollback(&self.id)?;
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Rolls back every participant.
+    pub fn abort(&self) -> Result<()> {
+        let participants = self.lock_participants()?;
+        match self.state() {
+            TransactionState::Active | TransactionState::Prepared => {}
+            _ => return Err(self.not_active()),
+        }
+        self.abort_participants(&participants)
+    }
+
+    fn abort_participants(&self, participants: &Participants) -> Result<()> {
+        self.set_state(TransactionState::Aborted);
+        self.rollback_all(participants)?;
+
+        match self.log() {
+            Some(log) => log.forget(&self.id),
+            None => Ok(()),
+        }
+    }
+
+    /// Rolls back every participant, even if some of them fail.
+    fn rollback_all(&self, participants: &Participants) -> Result<()> {
+        let mut result = Ok(());
+        for resource in participants.values() {
+            if let Err(e) = resource.rollback(&self.id) {
+                result = Err(e);
+            }
+        }
+        result
+    }
+
+    /// Aborts a transaction that is dropped before completing.
+    pub fn abort_unfinished(&self, kind: &str) {
+        if matches!(
+            self.state(),
+            TransactionState::Active | TransactionState::Prepared
+        ) {
+            tracing::warn!(
+                "{} {} dropped without commit or abort, aborting it",
+                kind,
+                self.id
+            );
+            if let Err(e) = self.abort() {
+                tracing::error!("Failed to abort {} {}: {}", kind, self.id, e);
+            }
+        }
+    }
+}
+
+/// A transaction to abort once `at` has passed, if it still exists by then.
+struct Deadline {
+    at: Instant,
+    core: Weak<TransactionCore>,
+}
+
+impl PartialEq for Deadline {
+    fn eq(&self, other: &Self) -> bool {
+        self.at == other.at
+    }
+}
+
+impl Eq for Deadline {}
+
+impl PartialOrd for Deadline {
+    fn partial_cmp(&self, other: &Self) -> Option<CmpOrdering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Deadline {
+    /// Reversed, so the heap yields the earliest deadline first.
+    fn cmp(&self, other: &Self) -> CmpOrdering {
+        other.at.cmp(&self.at)
+    }
+}
+
+/// Single background thread aborting every transaction that outlives its timeout.
+struct Reaper {
+    deadlines: Mutex<BinaryHeap<Deadline>>,
+    changed: Condvar,
+}
+
+impl Reaper {
+    fn start() -> Arc<Self> {
+        let reaper = Arc::new(Reaper {
+            deadlines: Mutex::new(BinaryHeap::new()),
+            changed: Condvar::new(),
+        });
+        let running = Arc::clone(&reaper);
+        thread::spawn(move || running.run());
+        reaper
+    }
+
+    fn schedule(&self, at: Instant, core: Weak<TransactionCore>) {
+        self.deadlines
+            .lock()
+            .expect("Failed to lock transaction deadlines")
+            .push(Deadline { at, core });
+        self.changed.notify_one();
+    }
+
+    fn run(&self) {
+        let mut deadlines = self
+            .deadlines
+            .lock()
+            .expect("Failed to lock transaction deadlines");
+        loop {
+            let now = Instant::now();
+            match deadlines.peek() {
+                None => {
+                    deadlines = self
+                        .changed
+                        .wait(deadlines)
+                        .expect("Failed to lock transaction deadlines");
+                    continue;
+                }
+                Some(next) if next.at > now => {
+                    let wait = next.at - now;
+                    deadlines = self
+                        .changed
+                        .wait_timeout(deadlines, wait)
+                        .expect("Failed to lock transaction deadlines")
+                        .0;
+                    continue;
+                }
+                Some(_) => {}
+            }
+
+            let due = deadlines.pop().expect("Deadline was just peeked");
+            drop(deadlines);
+            // the transaction decides whether it is really due, as its deadline may have
+            // been moved since this one was scheduled
+            if let Some(core) = due.core.upgrade() {
+                if let Err(e) = core
+                    .lock_participants()
+                    .and_then(|participants| core.expire(&participants))
+                {
+                    tracing::error!("Failed to abort timed out transaction {}: {}", core.id, e);
+                }
+            }
+            deadlines = self
+                .deadlines
+                .lock()
+                .expect("Failed to lock transaction deadlines");
+        }
+    }
+}
+
+/// Groups sends to one or more transactional queues so they become visible together on
+/// [`commit`](Transaction::commit) or not at all. Dropping a transaction that was neither
+/// committed nor aborted aborts it.
 pub struct Transaction<E> {
-    pub operations: Mutex<HashMap<String, Vec<Message<E>>>>,
+    core: Arc<TransactionCore>,
+    _marker: std::marker::PhantomData<E>,
 }
 
 impl<E> Transaction<E> {
     pub fn new() -> Self {
         Transaction {
-            operations: Mutex::new(HashMap::new()),
+            core: Arc::new(TransactionCore::new()),
+            _marker: std::marker::PhantomData,
         }
     }
+
+    /// Starts a transaction that is aborted if it hasn't committed `timeout` from now, like
+    /// [`with_timeout`](Self::with_timeout).
+    pub fn new_with_timeout(timeout: Duration) -> Self {
+        Self::new().with_timeout(timeout)
+    }
+
+    /// Makes the commit crash-safe: participants persist their work and the commit decision
+    /// is recorded in `log` before anything becomes visible. Queues built with a log bring
+    /// it in themselves.
+    pub fn with_log(self, log: Arc<TransactionLog>) -> Self {
+        self.core.set_log(log);
+        self
+    }
+
+    /// Aborts the transaction if it hasn't committed `timeout` after it was created; a later
+    /// commit fails with [`MSMQError::TransactionTimedOut`]. Overrides the default timeout
+    /// of the queues it uses.
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.core.set_timeout(timeout);
+        self
+    }
+
+    pub fn id(&self) -> &str {
+        self.core.id()
+    }
+
+    pub fn state(&self) -> TransactionState {
+        self.core.state()
+    }
+
+    pub(crate) fn enlist(&self, name: &str, resource: Arc<dyn ResourceManager>) -> Result<()> {
+        self.core.enlist(name, resource)
+    }
+
+    pub(crate) fn enlist_with<R>(
+        &self,
+        name: &str,
+        resource: Arc<dyn ResourceManager>,
+        work: impl FnOnce() -> Result<R>,
+    ) -> Result<R> {
+        self.core.enlist_with(name, resource, work)
+    }
+
+    pub(crate) fn set_default_timeout(&self, timeout: Duration) {
+        self.core.set_default_timeout(timeout);
+    }
+}
+
+impl<E> Default for Transaction<E> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl<E> Transaction<E> {
     pub fn commit(&self) -> Result<()> {
-        let operations = self.operations.lock().unwrap();
-        for (queue_name, messages) in operations.iter() {
-            //TODO: get queue from somewhere
+        self.core.commit()
+    }
+
+    /// Discards every send made under the transaction.
+    pub fn abort(&self) -> Result<()> {
+        self.core.abort()
+    }
+}
+
+impl<E> Drop for Transaction<E> {
+    fn drop(&mut self) {
+        self.core.abort_unfinished("Transaction");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_abort_discards_sends_to_every_queue() {
+        let orders = QueueBuilder::new("orders").with_transactional().build();
+        let invoices = QueueBuilder::new("invoices").with_transactional().build();
+
+        let txn = Transaction::new();
+        orders
+            .send_transactional(Message::new("Order"), &txn)
+            .unwrap();
+        invoices
+            .send_transactional(Message::new("Invoice"), &txn)
+            .unwrap();
+        txn.abort().unwrap();
+
+        assert_eq!(txn.state(), TransactionState::Aborted);
+        assert!(matches!(
+            txn.commit(),
+            Err(MSMQError::TransactionNotActive(_))
+        ));
+        assert!(orders
+            .send_transactional(Message::new("Late order"), &txn)
+            .is_err());
+        assert_eq!(orders.message_count().unwrap(), 0);
+        assert_eq!(invoices.message_count().unwrap(), 0);
+        assert!(orders.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_sends_racing_commit_are_delivered_or_rejected() {
+        let orders = QueueBuilder::new("orders").with_transactional().build();
+        let txn = Transaction::new();
+
+        let accepted = std::thread::scope(|scope| {
+            let sender = scope.spawn(|| {
+                (0..200)
+                    .filter(|_| {
+                        orders
+                            .send_transactional(Message::new("Order"), &txn)
+                            .is_ok()
+                    })
+                    .count()
+            });
+            txn.commit().unwrap();
+            sender.join().unwrap()
+        });
+
+        assert_eq!(orders.message_count().unwrap(), accepted);
+        assert!(orders.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_other_queue_with_an_enlisted_name_is_rejected() {
+        let orders = QueueBuilder::new("orders").with_transactional().build();
+        let other_orders = QueueBuilder::new("orders").with_transactional().build();
+
+        let txn = Transaction::new();
+        orders
+            .send_transactional(Message::new("Order"), &txn)
+            .unwrap();
+        orders
+            .clone()
+            .send_transactional(Message::new("Second order"), &txn)
+            .unwrap();
+        assert!(matches!(
+            other_orders.send_transactional(Message::new("Lost order"), &txn),
+            Err(MSMQError::NameInUse(name)) if name == "orders"
+        ));
+        txn.commit().unwrap();
+
+        assert_eq!(orders.message_count().unwrap(), 2);
+        assert_eq!(other_orders.message_count().unwrap(), 0);
+        assert!(other_orders.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_dropping_uncommitted_transaction_aborts_it() {
+        let orders = QueueBuilder::new("orders").with_transactional().build();
+        let invoices = QueueBuilder::new("invoices").with_transactional().build();
+
+        {
+            let txn = Transaction::new();
+            orders
+                .send_transactional(Message::new("Order"), &txn)
+                .unwrap();
+            invoices
+                .send_transactional(Message::new("Invoice"), &txn)
+                .unwrap();
+        }
+
+        assert_eq!(orders.message_count().unwrap(), 0);
+        assert_eq!(invoices.message_count().unwrap(), 0);
+        assert!(orders.pending.lock().unwrap().is_empty());
+        assert!(invoices.pending.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_recovery_completes_logged_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = Arc::new(TransactionLog::open(dir.path().join("txn.log")).unwrap());
+        let build = |name: &str| {
+            QueueBuilder::new(name)
+                .with_transactional()
+                .with_persistence(dir.path().join(name))
+                .with_transaction_log(Arc::clone(&log))
+                .build()
+        };
+
+        let mut orders = build("orders");
+        let invoices = build("invoices");
+        let seed = Transaction::new();
+        orders
+            .send_transactional(Message::new("Pending order"), &seed)
+            .unwrap();
+        seed.commit().unwrap();
+
+        let txn = Transaction::new().with_log(Arc::clone(&log));
+        orders.receive_transactional(&txn).unwrap().unwrap();
+        orders
+            .send_transactional(Message::new("Order"), &txn)
+            .unwrap();
+        invoices
+            .send_transactional(Message::new("Invoice"), &txn)
+            .unwrap();
+
+        // crash after the commit was recorded but before invoices applied it
+        orders.prepare(txn.id()).unwrap();
+        invoices.prepare(txn.id()).unwrap();
+        log.record_commit(txn.id()).unwrap();
+        orders.commit(txn.id()).unwrap();
+        std::mem::forget(txn);
+
+        let mut orders = build("orders");
+        let mut invoices = build("invoices");
+        assert_eq!(orders.receive().unwrap().content(), "Order");
+        assert!(orders.receive().is_none());
+        assert_eq!(invoices.receive().unwrap().content(), "Invoice");
+    }
+
+    #[test]
+    fn test_queue_with_a_log_brings_it_into_the_transaction() {
+        struct FailingCommit;
 
-            for message in messages {
-                //    queue.send(message.clone())?;
+        impl ResourceManager for FailingCommit {
+            fn prepare(&self, _txn_id: &str) -> Result<()> {
+                Ok(())
+            }
+
+            fn commit(&self, _txn_id: &str) -> Result<()> {
+                Err(MSMQError::Custom("Disk unplugged".to_string()))
+            }
+
+            fn rollback(&self, _txn_id: &str) -> Result<()> {
+                Ok(())
             }
         }
-        Ok(())
+
+        let dir = tempfile::tempdir().unwrap();
+        let log = Arc::new(TransactionLog::open(dir.path().join("txn.log")).unwrap());
+        let orders = QueueBuilder::new("orders")
+            .with_transactional()
+            .with_persistence(dir.path().join("orders"))
+            .with_transaction_log(Arc::clone(&log))
+            .build();
+        let other_log = Arc::new(TransactionLog::open(dir.path().join("other.log")).unwrap());
+        let invoices = QueueBuilder::new("invoices")
+            .with_transactional()
+            .with_transaction_log(other_log)
+            .build();
+
+        let txn = Transaction::new();
+        orders
+            .send_transactional(Message::new("Order"), &txn)
+            .unwrap();
+        assert!(invoices
+            .send_transactional(Message::new("Invoice"), &txn)
+            .is_err());
+        txn.enlist("disk", Arc::new(FailingCommit)).unwrap();
+        assert!(txn.commit().is_err());
+
+        // the commit was recorded, and stays until the failed participant recovers
+        assert!(log.is_committed(txn.id()));
+        assert_eq!(orders.message_count().unwrap(), 1);
+        assert_eq!(invoices.message_count().unwrap(), 0);
+    }
+
+    #[test]
+    fn test_recovery_rolls_back_unrecorded_commit() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = Arc::new(TransactionLog::open(dir.path().join("txn.log")).unwrap());
+        let build = || {
+            QueueBuilder::new("orders")
+                .with_transactional()
+                .with_persistence(dir.path().join("orders"))
+                .with_transaction_log(Arc::clone(&log))
+                .build()
+        };
+
+        let mut orders = build();
+        let seed = Transaction::new();
+        orders
+            .send_transactional(Message::new("Pending order"), &seed)
+            .unwrap();
+        seed.commit().unwrap();
+
+        let txn = Transaction::new().with_log(Arc::clone(&log));
+        orders.receive_transactional(&txn).unwrap().unwrap();
+        orders
+            .send_transactional(Message::new("Order"), &txn)
+            .unwrap();
+
+        // crash after preparing, before the commit was recorded
+        orders.prepare(txn.id()).unwrap();
+        std::mem::forget(txn);
+
+        let mut orders = build();
+        let restored = orders.receive().unwrap();
+        assert_eq!(restored.content(), "Pending order");
+        assert_eq!(restored.abort_count(), 1);
+        assert!(orders.receive().is_none());
+    }
+
+    #[test]
+    fn test_timed_out_transaction_is_aborted() {
+        let mut orders = QueueBuilder::new("orders").with_transactional().build();
+        let seed = Transaction::new();
+        orders
+            .send_transactional(Message::new("Order"), &seed)
+            .unwrap();
+        seed.commit().unwrap();
+
+        let txn = Transaction::new_with_timeout(Duration::from_millis(50));
+        orders.receive_transactional(&txn).unwrap().unwrap();
+        assert_eq!(orders.message_count().unwrap(), 0);
+
+        // aborted in the background, without touching the transaction
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(txn.state(), TransactionState::Aborted);
+        assert_eq!(orders.message_count().unwrap(), 1);
+        assert!(matches!(
+            txn.commit(),
+            Err(MSMQError::TransactionTimedOut(_))
+        ));
+    }
+
+    #[test]
+    fn test_earlier_deadline_is_not_held_up_by_a_later_one() {
+        let orders = QueueBuilder::new("orders").with_transactional().build();
+        let patient = Transaction::new_with_timeout(Duration::from_secs(60));
+        let hasty = Transaction::new_with_timeout(Duration::from_millis(20));
+        orders
+            .send_transactional(Message::new("Patient order"), &patient)
+            .unwrap();
+        orders
+            .send_transactional(Message::new("Hasty order"), &hasty)
+            .unwrap();
+
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(hasty.state(), TransactionState::Aborted);
+        assert_eq!(patient.state(), TransactionState::Active);
+        patient.commit().unwrap();
+        assert_eq!(orders.message_count().unwrap(), 1);
+    }
+
+    #[test]
+    fn test_queue_default_timeout_applies_to_transactions() {
+        let orders = QueueBuilder::new("orders")
+            .with_transactional()
+            .with_transaction_timeout(Duration::from_millis(20))
+            .build();
+
+        let txn = Transaction::new();
+        orders
+            .send_transactional(Message::new("Order"), &txn)
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        assert!(matches!(
+            txn.commit(),
+            Err(MSMQError::TransactionTimedOut(_))
+        ));
+        assert_eq!(orders.message_count().unwrap(), 0);
+
+        let txn = Transaction::new().with_timeout(Duration::from_secs(60));
+        orders
+            .send_transactional(Message::new("Order"), &txn)
+            .unwrap();
+        std::thread::sleep(Duration::from_millis(50));
+        txn.commit().unwrap();
+        assert_eq!(orders.message_count().unwrap(), 1);
     }
 }
@@ -0,0 +1,289 @@
+use crate::error::MSMQError;
+use crate::storage::{read_json, write_json};
+use crate::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+enum Decision {
+    /// Every participant prepared, but no outcome was decided yet.
+    Prepared,
+    Committed,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct LogEntry {
+    decision: Decision,
+    participants: Vec<String>,
+    /// Participants known to have applied the commit.
+    #[serde(default)]
+    acknowledged: Vec<String>,
+    /// Prepared on behalf of a coordinator elsewhere, which decides the outcome.
+    #[serde(default)]
+    branch: bool,
+}
+
+/// A transaction branch that prepared for a coordinator elsewhere but never heard its
+/// decision, usually because the coordinator crashed or became unreachable in between.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct InDoubtTransaction {
+    pub id: String,
+    /// Names of the enlisted participants.
+    pub participants: Vec<String>,
+}
+
+/// Durable coordinator state: which transactions prepared and which committed.
+///
+/// A transaction using a log first has every participant persist its buffered work and
+/// records that it prepared, then records its commit here and only then applies the work.
+/// Opening a log presumes the transactions it coordinated that prepared without recording a
+/// commit aborted, since nothing can commit them anymore; only branches prepared for another
+/// coordinator stay in doubt. Persistent queues built with the same log use it on startup to
+/// decide the outcome of work they find half-applied: it is completed if the commit was
+/// recorded, stays pending if its branch is in doubt, and is rolled back otherwise.
+pub struct TransactionLog {
+    path: PathBuf,
+    entries: Mutex<HashMap<String, LogEntry>>,
+}
+
+impl TransactionLog {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let log = Self::load(path)?;
+        log.update(|entries| {
+            let recorded = entries.len();
+            entries.retain(|txn_id, entry| {
+                let undecided = entry.decision == Decision::Prepared && !entry.branch;
+                if undecided {
+                    tracing::info!("Presuming transaction {} aborted", txn_id);
+                }
+                !undecided
+            });
+            Ok(entries.len() != recorded)
+        })?;
+        Ok(log)
+    }
+
+    /// Opens the log as it was left, for a coordinator that tells the participants of the
+    /// transactions it presumes aborted itself.
+    pub(crate) fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let entries = read_json(&path)?;
+        Ok(Self {
+            path,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn is_committed(&self, txn_id: &str) -> bool {
+        self.decision(txn_id) == Some(Decision::Committed)
+    }
+
+    pub fn is_in_doubt(&self, txn_id: &str) -> bool {
+        self.decision(txn_id) == Some(Decision::Prepared)
+    }
+
+    fn decision(&self, txn_id: &str) -> Option<Decision> {
+        self.entries
+            .lock()
+            .expect("Failed to lock transaction log")
+            .get(txn_id)
+            .map(|entry| entry.decision)
+    }
+
+    /// Lists the branches that prepared without an outcome being decided, ordered by id.
+    pub fn in_doubt(&self) -> Vec<InDoubtTransaction> {
+        let entries = self.entries.lock().expect("Failed to lock transaction log");
+        let mut in_doubt: Vec<_> = entries
+            .iter()
+            .filter(|(_, entry)| entry.decision == Decision::Prepared)
+            .map(|(id, entry)| InDoubtTransaction {
+                id: id.clone(),
+                participants: entry.participants.clone(),
+            })
+            .collect();
+        in_doubt.sort_by(|a, b| a.id.cmp(&b.id));
+        in_doubt
+    }
+
+    /// Every recorded transaction with its participants and whether it committed, for a
+    /// coordinator finishing them after a restart.
+    pub(crate) fn transactions(&self) -> Vec<(String, Vec<String>, bool)> {
+        self.entries
+            .lock()
+            .expect("Failed to lock transaction log")
+            .iter()
+            .map(|(id, entry)| {
+                (
+                    id.clone(),
+                    entry.participants.clone(),
+                    entry.decision == Decision::Committed,
+                )
+            })
+            .collect()
+    }
+
+    /// Manually decides that the in-doubt transaction `txn_id` commits. Participants apply
+    /// it once they recover.
+    pub fn force_commit(&self, txn_id: &str) -> Result<()> {
+        self.update(|entries| match entries.get_mut(txn_id) {
+            Some(entry) if entry.decision == Decision::Prepared => {
+                entry.decision = Decision::Committed;
+                Ok(true)
+            }
+            _ => Err(MSMQError::TransactionNotFound),
+        })
+    }
+
+    /// Manually decides that the in-doubt transaction `txn_id` aborts. Participants roll it
+    /// back once they recover.
+    pub fn force_abort(&self, txn_id: &str) -> Result<()> {
+        self.update(|entries| match entries.get(txn_id) {
+            Some(entry) if entry.decision == Decision::Prepared => {
+                entries.remove(txn_id);
+                Ok(true)
+            }
+            _ => Err(MSMQError::TransactionNotFound),
+        })
+    }
+
+    /// Durably records that every participant of `txn_id` prepared. A `branch` of a
+    /// transaction coordinated elsewhere stays in doubt across restarts until its outcome is
+    /// decided.
+    pub(crate) fn record_prepared(
+        &self,
+        txn_id: &str,
+        participants: Vec<String>,
+        branch: bool,
+    ) -> Result<()> {
+        self.update(|entries| {
+            entries.insert(
+                txn_id.to_string(),
+                LogEntry {
+                    decision: Decision::Prepared,
+                    participants,
+                    acknowledged: Vec::new(),
+                    branch,
+                },
+            );
+            Ok(true)
+        })
+    }
+
+    /// Durably records that `txn_id` committed. Once this returns the transaction will be
+    /// completed, even if the process dies before its work is applied.
+    pub(crate) fn record_commit(&self, txn_id: &str) -> Result<()> {
+        self.update(|entries| {
+            entries
+                .entry(txn_id.to_string())
+                .or_insert_with(|| LogEntry {
+                    decision: Decision::Committed,
+                    participants: Vec::new(),
+                    acknowledged: Vec::new(),
+                    branch: false,
+                })
+                .decision = Decision::Committed;
+            Ok(true)
+        })
+    }
+
+    /// Records that `participants` applied the committed `txn_id`, and drops its record once
+    /// every participant it lists has.
+    pub(crate) fn acknowledge(&self, txn_id: &str, participants: &[String]) -> Result<()> {
+        self.update(|entries| {
+            let Some(entry) = entries.get_mut(txn_id) else {
+                return Ok(false);
+            };
+            if entry.decision != Decision::Committed {
+                return Ok(false);
+            }
+            for participant in participants {
+                if !entry.acknowledged.contains(participant) {
+                    entry.acknowledged.push(participant.clone());
+                }
+            }
+            let finished = entry
+                .participants
+                .iter()
+                .all(|participant| entry.acknowledged.contains(participant));
+            if finished {
+                entries.remove(txn_id);
+            }
+            Ok(true)
+        })
+    }
+
+    /// Drops the record of `txn_id` once every participant has applied or rolled it back.
+    pub(crate) fn forget(&self, txn_id: &str) -> Result<()> {
+        self.update(|entries| Ok(entries.remove(txn_id).is_some()))
+    }
+
+    /// Runs `change` on the entries and writes them out if it reports a change.
+    fn update<F>(&self, change: F) -> Result<()>
+    where
+        F: FnOnce(&mut HashMap<String, LogEntry>) -> Result<bool>,
+    {
+        let mut entries = self
+            .entries
+            .lock()
+            .map_err(|e| MSMQError::Custom(e.to_string()))?;
+        if change(&mut entries)? {
+            write_json(&self.path, &*entries)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_commit_records_survive_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("transactions.log");
+
+        let log = TransactionLog::open(&path).unwrap();
+        log.record_commit("txn-1").unwrap();
+        log.record_commit("txn-2").unwrap();
+        log.forget("txn-2").unwrap();
+        log.record_prepared("txn-3", vec!["orders".to_string()], true)
+            .unwrap();
+        log.record_prepared("txn-4", vec!["orders".to_string()], false)
+            .unwrap();
+
+        let log = TransactionLog::open(&path).unwrap();
+        assert!(log.is_committed("txn-1"));
+        assert!(!log.is_committed("txn-2"));
+        assert!(log.is_in_doubt("txn-3"));
+        assert!(!log.is_in_doubt("txn-4"));
+        assert!(log.transactions().iter().all(|(id, _, _)| id != "txn-4"));
+        assert_eq!(
+            log.in_doubt(),
+            vec![InDoubtTransaction {
+                id: "txn-3".to_string(),
+                participants: vec!["orders".to_string()],
+            }]
+        );
+    }
+
+    #[test]
+    fn test_force_resolves_only_in_doubt_transactions() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = TransactionLog::open(dir.path().join("transactions.log")).unwrap();
+        log.record_prepared("txn-1", Vec::new(), true).unwrap();
+        log.record_prepared("txn-2", Vec::new(), true).unwrap();
+        log.record_commit("txn-3").unwrap();
+
+        log.force_commit("txn-1").unwrap();
+        log.force_abort("txn-2").unwrap();
+        assert!(log.is_committed("txn-1"));
+        assert!(!log.is_in_doubt("txn-2"));
+        assert!(log.in_doubt().is_empty());
+        assert!(matches!(
+            log.force_abort("txn-3"),
+            Err(MSMQError::TransactionNotFound)
+        ));
+    }
+}